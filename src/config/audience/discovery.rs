@@ -1,47 +1,122 @@
-use crate::config::audience::schema::{AudienceProvider, StaticAudienceProvider};
+use crate::config::audience::schema::{
+    AudienceConfiguration, AudienceProvider, AudienceRoute, FileAudienceProvider,
+    RoutedAudienceProvider, StaticAudienceProvider,
+};
 use jnt::{opaque_err, types};
 
-jnt::env!(
-    discover_audience_provider_str,
-    "AUDIENCE_PROVIDER",
-    "static"
-);
+jnt::env!(discover_audience_provider_str, "AUDIENCE_PROVIDER", "");
 jnt::env!(discover_audience_str, "AUDIENCE", "");
 jnt::env!(discover_audiences_str, "AUDIENCES", "");
+jnt::env!(discover_audience_file_str, "AUDIENCE_FILE", "");
+jnt::env!(discover_audience_routes_str, "AUDIENCE_ROUTES", "");
+jnt::env!(
+    discover_audience_refresh_schedule_str,
+    "AUDIENCE_REFRESH_SCHEDULE",
+    ""
+);
 
 type AudProviderResult = types::StdResult<Box<dyn AudienceProvider>>;
 
-fn discover_audience() -> Option<StaticAudienceProvider> {
+fn discover_audiences() -> Option<Vec<String>> {
     let audience_str = discover_audience_str();
 
-    if audience_str.is_empty() {
-        return None;
+    if !audience_str.is_empty() {
+        return Some(vec![audience_str]);
     }
 
-    Some(StaticAudienceProvider::new_single_aud(&audience_str))
-}
-
-fn discover_audiences() -> Option<StaticAudienceProvider> {
     let audiences_str = discover_audiences_str();
 
     if audiences_str.is_empty() {
         return None;
     }
 
-    let audiences: Vec<String> = audiences_str.split(",").map(|s| s.to_string()).collect();
-    Some(StaticAudienceProvider::new(audiences))
+    Some(audiences_str.split(",").map(|s| s.to_string()).collect())
 }
 
-fn discover_static_provider() -> AudProviderResult {
-    match discover_audience().or(discover_audiences()) {
-        Some(provider) => Ok(Box::new(provider)),
-        None => Err(opaque_err!("No audience configured for static provider")),
+fn discover_routes() -> types::StdResult<Option<Vec<AudienceRoute>>> {
+    let routes_str = discover_audience_routes_str();
+
+    if routes_str.is_empty() {
+        return Ok(None);
     }
+
+    let mut routes: Vec<AudienceRoute> = vec![];
+
+    for route_str in routes_str.split(";").filter(|s| !s.trim().is_empty()) {
+        routes.push(route_str.parse()?);
+    }
+
+    Ok(Some(routes))
 }
 
-pub fn discover_audience_provider() -> AudProviderResult {
-    match discover_audience_provider_str().to_lowercase().as_str() {
-        "static" => discover_static_provider(),
+fn apply_environment_overrides(
+    mut configuration: AudienceConfiguration,
+) -> types::StdResult<AudienceConfiguration> {
+    let provider = discover_audience_provider_str();
+    let path = discover_audience_file_str();
+    let refresh_schedule = discover_audience_refresh_schedule_str();
+
+    if !provider.is_empty() {
+        configuration.provider = provider;
+    }
+
+    if let Some(audiences) = discover_audiences() {
+        configuration.audiences = audiences;
+    }
+
+    if !path.is_empty() {
+        configuration.file = Some(path);
+    }
+
+    if !refresh_schedule.is_empty() {
+        configuration.refresh_schedule = refresh_schedule;
+    }
+
+    if let Some(routes) = discover_routes()? {
+        configuration.routes = routes;
+    }
+
+    Ok(configuration)
+}
+
+fn new_static_provider(configuration: AudienceConfiguration) -> AudProviderResult {
+    if configuration.audiences.is_empty() {
+        return Err(opaque_err!("No audience configured for static provider"));
+    }
+
+    Ok(Box::new(StaticAudienceProvider::new(
+        configuration.audiences,
+    )))
+}
+
+fn new_file_provider(configuration: AudienceConfiguration) -> AudProviderResult {
+    let Some(path) = configuration.file else {
+        return Err(opaque_err!("No audience file configured for file provider"));
+    };
+
+    Ok(Box::new(FileAudienceProvider::new(
+        &path,
+        &configuration.refresh_schedule,
+    )?))
+}
+
+fn new_routed_provider(configuration: AudienceConfiguration) -> AudProviderResult {
+    if configuration.routes.is_empty() {
+        return Err(opaque_err!("No audience routes configured for routes provider"));
+    }
+
+    Ok(Box::new(RoutedAudienceProvider::new(configuration.routes)))
+}
+
+/// Builds the audience provider from the configuration file section,
+/// with environment variables taking precedence over file values.
+pub fn discover_audience_provider(configuration: AudienceConfiguration) -> AudProviderResult {
+    let configuration = apply_environment_overrides(configuration)?;
+
+    match configuration.provider.to_lowercase().as_str() {
+        "static" => new_static_provider(configuration),
+        "file" => new_file_provider(configuration),
+        "routes" => new_routed_provider(configuration),
         _ => Err(opaque_err!("Invalid audience provider")),
     }
 }
@@ -1,5 +1,55 @@
+use std::fs;
+use std::str::FromStr;
+use std::sync::{PoisonError, RwLock};
+
+use jnt::{opaque_err, types};
+use serde::Deserialize;
+
+use crate::server::request::has_path_prefix;
+
 pub trait AudienceProvider: Sync + Send {
     fn get_audiences(&self) -> Vec<String>;
+
+    /// Returns the audiences allowed for a request target, or None
+    /// if the target should be rejected outright
+    fn get_route_audiences(&self, _authority: &str, _path: &str) -> Option<Vec<String>> {
+        Some(self.get_audiences())
+    }
+
+    /// A hook to trigger the provider to reload its audiences.
+    /// Returns a bool signalling if the audiences changed.
+    fn refresh(&self) -> types::BoolResult {
+        Ok(false)
+    }
+
+    /// The cron schedule on which refresh should be triggered, if any
+    fn get_refresh_schedule(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Selects and configures the audience provider. Every field can be
+/// overridden by its matching environment variable.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudienceConfiguration {
+    pub provider: String,
+    pub audiences: Vec<String>,
+    pub file: Option<String>,
+    pub refresh_schedule: String,
+    pub routes: Vec<AudienceRoute>,
+}
+
+impl Default for AudienceConfiguration {
+    fn default() -> Self {
+        AudienceConfiguration {
+            provider: "static".to_string(),
+            audiences: vec![],
+            file: None,
+            refresh_schedule: "*/30 * * * * *".to_string(),
+            routes: vec![],
+        }
+    }
 }
 
 pub struct StaticAudienceProvider {
@@ -10,10 +60,6 @@ impl StaticAudienceProvider {
     pub fn new(audiences: Vec<String>) -> Self {
         StaticAudienceProvider { audiences }
     }
-
-    pub fn new_single_aud(audience: &str) -> Self {
-        Self::new(vec![audience.to_string()])
-    }
 }
 
 impl AudienceProvider for StaticAudienceProvider {
@@ -21,3 +67,313 @@ impl AudienceProvider for StaticAudienceProvider {
         self.audiences.clone()
     }
 }
+
+/// Reads audience tags from a file, one per line or comma separated.
+/// Blank lines and lines starting with `#` are ignored.
+pub struct FileAudienceProvider {
+    path: String,
+    refresh_schedule: String,
+    audiences: RwLock<Vec<String>>,
+}
+
+fn read_audience_file(path: &str) -> types::StdResult<Vec<String>> {
+    let audiences: Vec<String> = fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split(','))
+        .map(|audience| audience.trim().to_string())
+        .filter(|audience| !audience.is_empty())
+        .collect();
+
+    if audiences.is_empty() {
+        return Err(opaque_err!("audience file contains no audiences"));
+    }
+
+    Ok(audiences)
+}
+
+impl FileAudienceProvider {
+    pub fn new(path: &str, refresh_schedule: &str) -> types::StdResult<Self> {
+        Ok(FileAudienceProvider {
+            path: path.to_string(),
+            refresh_schedule: refresh_schedule.to_string(),
+            audiences: RwLock::new(read_audience_file(path)?),
+        })
+    }
+}
+
+impl AudienceProvider for FileAudienceProvider {
+    fn get_audiences(&self) -> Vec<String> {
+        self.audiences
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn refresh(&self) -> types::BoolResult {
+        let latest = read_audience_file(&self.path)?;
+
+        if latest == self.get_audiences() {
+            return Ok(false);
+        }
+
+        *self
+            .audiences
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = latest;
+
+        Ok(true)
+    }
+
+    fn get_refresh_schedule(&self) -> Option<String> {
+        Some(self.refresh_schedule.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+pub struct AudienceRoute {
+    pub authority: String,
+    pub path_prefix: String,
+    pub audiences: Vec<String>,
+}
+
+impl AudienceRoute {
+    fn matches_authority(&self, authority: &str) -> bool {
+        if self.authority == "*" {
+            return true;
+        }
+
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => authority,
+        };
+
+        self.authority.eq_ignore_ascii_case(authority) || self.authority.eq_ignore_ascii_case(host)
+    }
+
+    fn matches(&self, authority: &str, path: &str) -> bool {
+        self.matches_authority(authority) && has_path_prefix(path, &self.path_prefix)
+    }
+}
+
+impl FromStr for AudienceRoute {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parses a route in the form `host[/path/prefix]=aud1,aud2`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, audiences_str) = s
+            .split_once('=')
+            .ok_or(format!("audience route '{s}' is missing '='"))?;
+
+        let (authority, path_prefix) = match target.trim().find('/') {
+            Some(index) => target.trim().split_at(index),
+            None => (target.trim(), "/"),
+        };
+
+        let audiences: Vec<String> = audiences_str
+            .split(',')
+            .map(|audience| audience.trim().to_string())
+            .filter(|audience| !audience.is_empty())
+            .collect();
+
+        if authority.is_empty() {
+            return Err(format!("audience route '{s}' has no host").into());
+        }
+
+        if audiences.is_empty() {
+            return Err(format!("audience route '{s}' has no audiences").into());
+        }
+
+        Ok(AudienceRoute {
+            authority: authority.to_lowercase(),
+            path_prefix: path_prefix.to_string(),
+            audiences,
+        })
+    }
+}
+
+impl TryFrom<String> for AudienceRoute {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Maps request hosts and path prefixes to the audiences allowed for them.
+/// The longest matching path prefix wins, and exact hosts take priority over `*`.
+pub struct RoutedAudienceProvider {
+    routes: Vec<AudienceRoute>,
+}
+
+impl RoutedAudienceProvider {
+    pub fn new(mut routes: Vec<AudienceRoute>) -> Self {
+        routes.sort_by(|a, b| {
+            (b.authority != "*")
+                .cmp(&(a.authority != "*"))
+                .then(b.path_prefix.len().cmp(&a.path_prefix.len()))
+        });
+
+        RoutedAudienceProvider { routes }
+    }
+}
+
+impl AudienceProvider for RoutedAudienceProvider {
+    fn get_audiences(&self) -> Vec<String> {
+        let mut audiences: Vec<String> = vec![];
+
+        for route in &self.routes {
+            for audience in &route.audiences {
+                if !audiences.contains(audience) {
+                    audiences.push(audience.to_string());
+                }
+            }
+        }
+
+        audiences
+    }
+
+    fn get_route_audiences(&self, authority: &str, path: &str) -> Option<Vec<String>> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(authority, path))
+            .map(|route| route.audiences.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_provider(routes: &[&str]) -> RoutedAudienceProvider {
+        RoutedAudienceProvider::new(routes.iter().map(|route| route.parse().unwrap()).collect())
+    }
+
+    fn new_audience_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("extauthz-cfzt-{name}-{}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn reads_audience_files() {
+        let path = new_audience_file(
+            "audiences",
+            "# Production\n\n  aud1 \naud2, aud3,\n   # aud4\n\t\n",
+        );
+
+        assert_eq!(
+            read_audience_file(&path).unwrap(),
+            vec!["aud1", "aud2", "aud3"]
+        );
+
+        fs::write(&path, "# No audiences\n\n").unwrap();
+        assert!(read_audience_file(&path).is_err());
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn keeps_audiences_when_a_reload_fails() {
+        let path = new_audience_file("audience-reload", "aud1\n");
+        let provider = FileAudienceProvider::new(&path, "* * * * * *").unwrap();
+
+        fs::write(&path, "aud1\naud2\n").unwrap();
+        assert!(provider.refresh().unwrap());
+        assert!(!provider.refresh().unwrap());
+        assert_eq!(provider.get_audiences(), vec!["aud1", "aud2"]);
+
+        fs::write(&path, "# Emptied\n").unwrap();
+        assert!(provider.refresh().is_err());
+        assert_eq!(provider.get_audiences(), vec!["aud1", "aud2"]);
+
+        fs::remove_file(&path).unwrap();
+        assert!(provider.refresh().is_err());
+        assert_eq!(provider.get_audiences(), vec!["aud1", "aud2"]);
+    }
+
+    #[test]
+    fn parses_route_with_default_path_prefix() {
+        let route: AudienceRoute = "App.Example.com=aud1, aud2".parse().unwrap();
+
+        assert_eq!(route.authority, "app.example.com");
+        assert_eq!(route.path_prefix, "/");
+        assert_eq!(route.audiences, vec!["aud1", "aud2"]);
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        assert!("app.example.com".parse::<AudienceRoute>().is_err());
+        assert!("=aud1".parse::<AudienceRoute>().is_err());
+        assert!("app.example.com=".parse::<AudienceRoute>().is_err());
+    }
+
+    #[test]
+    fn matches_authority_with_and_without_port() {
+        let route: AudienceRoute = "app.example.com=aud1".parse().unwrap();
+
+        assert!(route.matches("app.example.com", "/"));
+        assert!(route.matches("APP.example.com:8443", "/"));
+        assert!(!route.matches("other.example.com", "/"));
+    }
+
+    #[test]
+    fn matches_path_prefix_on_segment_boundaries() {
+        let route: AudienceRoute = "*/admin=aud1".parse().unwrap();
+
+        assert!(route.matches("app.example.com", "/admin"));
+        assert!(route.matches("app.example.com", "/admin/users"));
+        assert!(!route.matches("app.example.com", "/administrator"));
+        assert!(!route.matches("app.example.com", "/"));
+
+        let route: AudienceRoute = "*/admin/=aud1".parse().unwrap();
+
+        assert!(route.matches("app.example.com", "/admin/users"));
+        assert!(!route.matches("app.example.com", "/administrator"));
+    }
+
+    #[test]
+    fn prefers_longest_prefix_and_exact_host() {
+        let provider = new_provider(&[
+            "*=wildcard",
+            "app.example.com=root",
+            "app.example.com/api=api",
+            "*/api/internal=internal",
+        ]);
+
+        assert_eq!(
+            provider.get_route_audiences("app.example.com", "/api/v1"),
+            Some(vec!["api".to_string()])
+        );
+        assert_eq!(
+            provider.get_route_audiences("app.example.com", "/apiv2"),
+            Some(vec!["root".to_string()])
+        );
+        assert_eq!(
+            provider.get_route_audiences("app.example.com", "/api/internal"),
+            Some(vec!["api".to_string()])
+        );
+        assert_eq!(
+            provider.get_route_audiences("other.example.com", "/api/internal/x"),
+            Some(vec!["internal".to_string()])
+        );
+        assert_eq!(
+            provider.get_route_audiences("other.example.com", "/"),
+            Some(vec!["wildcard".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_unrouted_targets() {
+        let provider = new_provider(&["app.example.com/api=api"]);
+
+        assert_eq!(provider.get_route_audiences("app.example.com", "/"), None);
+        assert_eq!(
+            provider.get_route_audiences("other.example.com", "/api"),
+            None
+        );
+    }
+}
@@ -1,28 +1,520 @@
 use super::schema::Configuration;
-use jnt::types;
+use jnt::{opaque_err, types};
 use jnt::extensions::contains::ConstHashSetExt;
+use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
+use std::time::Duration;
 use phf::phf_set;
 
-use crate::config::bootstrap::schema::TimeConstraintMode;
+use crate::config::bootstrap::schema::{
+    parse_http_status, AuditConfiguration, BypassRule, CacheConfiguration, CustomClaimMode, DeniedResponseConfiguration,
+    ForwardAuthHeaders, HeaderConfiguration, HealthConfiguration, IdentityConfiguration, KeySyncConfiguration,
+    RateLimitConfiguration, RateLimitKey, ServiceGrant, TimeConstraintMode, TlsConfiguration, TokenSource,
+    ValidatorConfiguration,
+};
 
 const TRUTHY_STRS: ConstHashSetExt<&str> = ConstHashSetExt::<&str>(phf_set!("yes", "on", "enabled",));
 
 jnt::bool_parser!(bool_parser, TRUTHY_STRS);
 
 jnt::env!(discover_listener_str, "LISTENER", "tcp://[::1]:10000");
+jnt::env!(discover_team_names_str, "TEAM_NAMES", "");
 jnt::env!(discover_static_keys_str, "STATIC_KEYS", "");
 jnt::env!(discover_iat_validation_str, "NBF_VALIDATION", "strict");
 jnt::env!(discover_exp_validation_str, "EXP_VALIDATION", "strict");
 jnt::env!(discover_sync_schedule_str, "SYNC_SCHEDULE", "0 0 0 * * *");
 jnt::env!(discover_enable_proxy_discovery, "ENABLE_PROXY_DISCOVERY", bool, false, bool_parser);
+jnt::env!(discover_unauthenticated_status_str, "UNAUTHENTICATED_STATUS", "");
+jnt::env!(discover_forbidden_status_str, "FORBIDDEN_STATUS", "");
+jnt::env!(discover_rate_limited_status_str, "RATE_LIMITED_STATUS", "");
+jnt::env!(discover_denied_body_str, "DENIED_BODY", "");
+jnt::env!(discover_denied_headers_str, "DENIED_HEADERS", "");
+jnt::env!(discover_health_check_schedule_str, "HEALTH_CHECK_SCHEDULE", "");
+jnt::env!(discover_sync_failure_threshold_str, "SYNC_FAILURE_THRESHOLD", "");
+jnt::env!(discover_metrics_listener_str, "METRICS_LISTENER", "");
+jnt::env!(discover_decision_cache_size_str, "DECISION_CACHE_SIZE", "");
+jnt::env!(discover_decision_cache_ttl_str, "DECISION_CACHE_TTL", "");
+jnt::env!(discover_token_sources_str, "TOKEN_SOURCES", "");
+jnt::env!(discover_time_leeway_str, "TIME_LEEWAY", "");
+jnt::env!(discover_shutdown_timeout_str, "SHUTDOWN_TIMEOUT", "");
+jnt::env!(discover_tls_cert_file_str, "TLS_CERT_FILE", "");
+jnt::env!(discover_tls_key_file_str, "TLS_KEY_FILE", "");
+jnt::env!(discover_tls_client_ca_file_str, "TLS_CLIENT_CA_FILE", "");
+jnt::env!(discover_tls_reload_schedule_str, "TLS_RELOAD_SCHEDULE", "");
+jnt::env!(discover_max_token_age_str, "MAX_TOKEN_AGE", "");
+jnt::env!(discover_header_prefix_str, "HEADER_PREFIX", "");
+jnt::env!(discover_header_names_str, "HEADER_NAMES", "");
+jnt::env!(discover_header_omit_str, "HEADER_OMIT", "");
+jnt::env!(discover_custom_claim_mode_str, "CUSTOM_CLAIM_MODE", "");
+jnt::env!(discover_custom_claim_separator_str, "CUSTOM_CLAIM_SEPARATOR", "");
+jnt::env!(discover_remove_token_header, "REMOVE_TOKEN_HEADER", bool, false, bool_parser);
+jnt::env!(discover_identity_enrichment, "IDENTITY_ENRICHMENT", bool, false, bool_parser);
+jnt::env!(discover_identity_base_url_str, "IDENTITY_BASE_URL", "");
+jnt::env!(discover_identity_cache_size_str, "IDENTITY_CACHE_SIZE", "");
+jnt::env!(discover_identity_timeout_str, "IDENTITY_TIMEOUT", "");
+jnt::env!(discover_forward_auth_listener_str, "FORWARD_AUTH_LISTENER", "");
+jnt::env!(discover_forward_auth_headers_str, "FORWARD_AUTH_HEADERS", "");
+jnt::env!(discover_rate_limit, "RATE_LIMIT", bool, false, bool_parser);
+jnt::env!(discover_rate_limit_key_str, "RATE_LIMIT_KEY", "");
+jnt::env!(discover_rate_limit_burst_str, "RATE_LIMIT_BURST", "");
+jnt::env!(discover_rate_limit_refill_str, "RATE_LIMIT_REFILL", "");
+jnt::env!(discover_rate_limit_max_sources_str, "RATE_LIMIT_MAX_SOURCES", "");
+jnt::env!(discover_rate_limit_trusted_hops_str, "RATE_LIMIT_TRUSTED_HOPS", "");
+jnt::env!(discover_rate_limit_sweep_schedule_str, "RATE_LIMIT_SWEEP_SCHEDULE", "");
+jnt::env!(discover_service_allowlist_str, "SERVICE_ALLOWLIST", "");
+jnt::env!(discover_bypass_rules_str, "BYPASS_RULES", "");
+jnt::env!(discover_audit_log, "AUDIT_LOG", bool, false, bool_parser);
+jnt::env!(discover_audit_file_str, "AUDIT_FILE", "");
+jnt::env!(discover_audit_max_file_size_str, "AUDIT_MAX_FILE_SIZE", "");
+jnt::env!(discover_audit_max_files_str, "AUDIT_MAX_FILES", "");
+jnt::env!(discover_audit_buffer_size_str, "AUDIT_BUFFER_SIZE", "");
+jnt::env!(discover_sync_retry_attempts_str, "SYNC_RETRY_ATTEMPTS", "");
+jnt::env!(discover_sync_retry_backoff_str, "SYNC_RETRY_BACKOFF", "");
+jnt::env!(discover_sync_retry_max_backoff_str, "SYNC_RETRY_MAX_BACKOFF", "");
+jnt::env!(discover_key_refresh_interval_str, "KEY_REFRESH_INTERVAL", "");
+jnt::env!(discover_sync_timeout_str, "SYNC_TIMEOUT", "");
 
-pub fn discover_bootstrap_configuration() -> types::StdResult<Configuration> {
+fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        return None;
+    }
+
+    Some(s)
+}
+
+fn is_env_set(var: &str) -> bool {
+    env::var_os(var).is_some_and(|value| !value.is_empty())
+}
+
+fn parse_seconds(s: &str, var: &str) -> types::StdResult<Duration> {
+    let seconds: u64 = s.parse().map_err(|e| format!("invalid {var}: {e}"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+fn apply_tls_overrides(tls: &mut Option<TlsConfiguration>) -> types::EmptyResult {
+    let cert_file = non_empty(discover_tls_cert_file_str());
+    let key_file = non_empty(discover_tls_key_file_str());
+
+    match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let configuration = tls.get_or_insert_with(|| TlsConfiguration::new(&cert_file, &key_file));
+            configuration.cert_file = cert_file;
+            configuration.key_file = key_file;
+        }
+        (None, None) => {}
+        _ => return Err(opaque_err!("TLS_CERT_FILE and TLS_KEY_FILE must be set together")),
+    }
+
+    let client_ca_file = non_empty(discover_tls_client_ca_file_str());
+    let reload_schedule = non_empty(discover_tls_reload_schedule_str());
+
+    if client_ca_file.is_none() && reload_schedule.is_none() {
+        return Ok(());
+    }
+
+    let Some(configuration) = tls else {
+        return Err(opaque_err!("TLS_CLIENT_CA_FILE and TLS_RELOAD_SCHEDULE require TLS_CERT_FILE and TLS_KEY_FILE"));
+    };
+
+    if client_ca_file.is_some() {
+        configuration.client_ca_file = client_ca_file;
+    }
+
+    if let Some(reload_schedule) = reload_schedule {
+        configuration.reload_schedule = reload_schedule;
+    }
+
+    Ok(())
+}
+
+fn apply_cache_overrides(cache: &mut CacheConfiguration) -> types::EmptyResult {
+    if let Some(size_str) = non_empty(discover_decision_cache_size_str()) {
+        cache.max_entries = size_str.parse().map_err(|e| format!("invalid DECISION_CACHE_SIZE: {e}"))?;
+    }
+
+    if let Some(ttl_str) = non_empty(discover_decision_cache_ttl_str()) {
+        cache.max_ttl = parse_seconds(&ttl_str, "DECISION_CACHE_TTL")?;
+    }
+
+    Ok(())
+}
+
+fn apply_rate_limit_overrides(rate_limit: &mut RateLimitConfiguration) -> types::EmptyResult {
+    if is_env_set("RATE_LIMIT") {
+        rate_limit.enabled = discover_rate_limit();
+    }
+
+    if let Some(key_str) = non_empty(discover_rate_limit_key_str()) {
+        rate_limit.key = RateLimitKey::from_str(&key_str)?;
+    }
+
+    if let Some(burst_str) = non_empty(discover_rate_limit_burst_str()) {
+        rate_limit.burst = burst_str.parse().map_err(|e| format!("invalid RATE_LIMIT_BURST: {e}"))?;
+    }
+
+    if let Some(refill_str) = non_empty(discover_rate_limit_refill_str()) {
+        rate_limit.refill_per_second = refill_str.parse().map_err(|e| format!("invalid RATE_LIMIT_REFILL: {e}"))?;
+    }
+
+    if let Some(size_str) = non_empty(discover_rate_limit_max_sources_str()) {
+        rate_limit.max_sources = size_str.parse().map_err(|e| format!("invalid RATE_LIMIT_MAX_SOURCES: {e}"))?;
+    }
+
+    if let Some(hops_str) = non_empty(discover_rate_limit_trusted_hops_str()) {
+        rate_limit.trusted_hops = hops_str.parse().map_err(|e| format!("invalid RATE_LIMIT_TRUSTED_HOPS: {e}"))?;
+    }
+
+    if let Some(schedule) = non_empty(discover_rate_limit_sweep_schedule_str()) {
+        rate_limit.sweep_schedule = schedule;
+    }
+
+    if rate_limit.trusted_hops == 0 {
+        return Err(opaque_err!("RATE_LIMIT_TRUSTED_HOPS must be at least 1"));
+    }
+
+    if rate_limit.burst == 0 {
+        return Err(opaque_err!("RATE_LIMIT_BURST must be at least 1"));
+    }
+
+    if rate_limit.refill_per_second.is_nan() || rate_limit.refill_per_second <= 0.0 {
+        return Err(opaque_err!("RATE_LIMIT_REFILL must be greater than 0"));
+    }
+
+    Ok(())
+}
+
+fn apply_audit_overrides(audit: &mut AuditConfiguration) -> types::EmptyResult {
+    if is_env_set("AUDIT_LOG") {
+        audit.enabled = discover_audit_log();
+    }
+
+    if let Some(file) = non_empty(discover_audit_file_str()) {
+        audit.file = Some(file);
+    }
+
+    if let Some(size_str) = non_empty(discover_audit_max_file_size_str()) {
+        audit.max_file_size = size_str.parse().map_err(|e| format!("invalid AUDIT_MAX_FILE_SIZE: {e}"))?;
+    }
+
+    if let Some(files_str) = non_empty(discover_audit_max_files_str()) {
+        audit.max_files = files_str.parse().map_err(|e| format!("invalid AUDIT_MAX_FILES: {e}"))?;
+    }
+
+    if let Some(size_str) = non_empty(discover_audit_buffer_size_str()) {
+        audit.buffer_size = size_str.parse().map_err(|e| format!("invalid AUDIT_BUFFER_SIZE: {e}"))?;
+    }
+
+    Ok(())
+}
+
+fn apply_key_sync_overrides(key_sync: &mut KeySyncConfiguration) -> types::EmptyResult {
+    if let Some(attempts_str) = non_empty(discover_sync_retry_attempts_str()) {
+        key_sync.retry_attempts = attempts_str.parse().map_err(|e| format!("invalid SYNC_RETRY_ATTEMPTS: {e}"))?;
+    }
+
+    if let Some(backoff_str) = non_empty(discover_sync_retry_backoff_str()) {
+        key_sync.retry_backoff = parse_seconds(&backoff_str, "SYNC_RETRY_BACKOFF")?;
+    }
+
+    if let Some(backoff_str) = non_empty(discover_sync_retry_max_backoff_str()) {
+        key_sync.retry_max_backoff = parse_seconds(&backoff_str, "SYNC_RETRY_MAX_BACKOFF")?;
+    }
+
+    if let Some(interval_str) = non_empty(discover_key_refresh_interval_str()) {
+        key_sync.refresh_interval = parse_seconds(&interval_str, "KEY_REFRESH_INTERVAL")?;
+    }
+
+    if let Some(timeout_str) = non_empty(discover_sync_timeout_str()) {
+        key_sync.timeout = parse_seconds(&timeout_str, "SYNC_TIMEOUT")?;
+    }
+
+    Ok(())
+}
+
+fn apply_header_overrides(headers: &mut HeaderConfiguration) -> types::EmptyResult {
+    // An explicitly empty prefix is meaningful, so presence is checked rather than emptiness
+    if env::var_os("HEADER_PREFIX").is_some() {
+        headers.prefix = discover_header_prefix_str();
+    }
+
+    if let Some(names_str) = non_empty(discover_header_names_str()) {
+        headers.names.clear();
+
+        for name_str in names_str.split(";").filter(|s| !s.trim().is_empty()) {
+            let (field, name) = name_str
+                .split_once("=")
+                .ok_or(format!("header name '{name_str}' is missing '='"))?;
+            headers.names.insert(field.trim().to_string(), name.trim().to_string());
+        }
+    }
+
+    if let Some(omit_str) = non_empty(discover_header_omit_str()) {
+        headers.omit = omit_str.split(",").map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+    }
+
+    if let Some(mode_str) = non_empty(discover_custom_claim_mode_str()) {
+        headers.custom_claims = CustomClaimMode::from_str(&mode_str)?;
+    }
+
+    if let Some(separator) = non_empty(discover_custom_claim_separator_str()) {
+        headers.custom_claim_separator = separator;
+    }
+
+    headers.validate()
+}
+
+fn apply_token_source_overrides(token_sources: &mut Vec<TokenSource>) -> types::EmptyResult {
+    if let Some(sources_str) = non_empty(discover_token_sources_str()) {
+        let mut sources: Vec<TokenSource> = vec![];
+
+        for source_str in sources_str.split(",").filter(|s| !s.trim().is_empty()) {
+            sources.push(TokenSource::from_str(source_str).map_err(|e| format!("invalid TOKEN_SOURCES: {e}"))?);
+        }
+
+        *token_sources = sources;
+    }
+
+    if token_sources.is_empty() {
+        return Err(opaque_err!("at least one token source must be configured"));
+    }
+
+    Ok(())
+}
+
+fn apply_identity_overrides(identity: &mut IdentityConfiguration) -> types::EmptyResult {
+    if is_env_set("IDENTITY_ENRICHMENT") {
+        identity.enabled = discover_identity_enrichment();
+    }
+
+    if let Some(base_url) = non_empty(discover_identity_base_url_str()) {
+        identity.base_url = Some(base_url);
+    }
+
+    if let Some(size_str) = non_empty(discover_identity_cache_size_str()) {
+        identity.max_entries = size_str.parse().map_err(|e| format!("invalid IDENTITY_CACHE_SIZE: {e}"))?;
+    }
+
+    if let Some(timeout_str) = non_empty(discover_identity_timeout_str()) {
+        identity.timeout = parse_seconds(&timeout_str, "IDENTITY_TIMEOUT")?;
+    }
+
+    Ok(())
+}
+
+fn apply_health_overrides(health: &mut HealthConfiguration) -> types::EmptyResult {
+    if let Some(schedule) = non_empty(discover_health_check_schedule_str()) {
+        health.check_schedule = schedule;
+    }
+
+    if let Some(threshold_str) = non_empty(discover_sync_failure_threshold_str()) {
+        health.sync_failure_threshold = parse_seconds(&threshold_str, "SYNC_FAILURE_THRESHOLD")?;
+    }
+
+    Ok(())
+}
+
+fn apply_denied_response_overrides(denied_response: &mut DeniedResponseConfiguration) -> types::EmptyResult {
+    if let Some(status_str) = non_empty(discover_unauthenticated_status_str()) {
+        denied_response.unauthenticated_status = parse_http_status(&status_str).map_err(|e| format!("invalid UNAUTHENTICATED_STATUS: {e}"))?;
+    }
+
+    if let Some(status_str) = non_empty(discover_forbidden_status_str()) {
+        denied_response.forbidden_status = parse_http_status(&status_str).map_err(|e| format!("invalid FORBIDDEN_STATUS: {e}"))?;
+    }
+
+    if let Some(status_str) = non_empty(discover_rate_limited_status_str()) {
+        denied_response.rate_limited_status = parse_http_status(&status_str).map_err(|e| format!("invalid RATE_LIMITED_STATUS: {e}"))?;
+    }
+
+    if let Some(body_str) = non_empty(discover_denied_body_str()) {
+        denied_response.body = Some(body_str);
+    }
+
+    if let Some(headers_str) = non_empty(discover_denied_headers_str()) {
+        let mut headers: Vec<(String, String)> = vec![];
+
+        for header_str in headers_str.split(";").filter(|s| !s.trim().is_empty()) {
+            let (name, value) = header_str
+                .split_once(":")
+                .ok_or(format!("denied response header '{header_str}' is missing ':'"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        denied_response.headers = headers;
+    }
+
+    Ok(())
+}
+
+fn discover_nbf_validation() -> types::StdResult<TimeConstraintMode> {
+    Ok(TimeConstraintMode::from_str(&discover_iat_validation_str()).map_err(|e| format!("invalid NBF_VALIDATION: {e}"))?)
+}
+
+fn discover_exp_validation() -> types::StdResult<TimeConstraintMode> {
+    Ok(TimeConstraintMode::from_str(&discover_exp_validation_str()).map_err(|e| format!("invalid EXP_VALIDATION: {e}"))?)
+}
+
+/// Applies the validator level environment variables that are set on top
+/// of a configuration loaded from file.
+fn apply_validator_overrides(mut configuration: Configuration) -> types::StdResult<Configuration> {
+    if is_env_set("TEAM_NAME") || is_env_set("TEAM_NAMES") {
+        // Only the teams are replaced, the file's common settings still apply
+        let mut validator = discover_validator_configuration()?.validator;
+
+        if let (Some(file_validator), Some(validator)) = (&configuration.validator, &mut validator) {
+            validator.common_mut().proxy_discovery = file_validator.common().proxy_discovery;
+        }
+
+        configuration.validator = validator;
+    } else if is_env_set("STATIC_KEYS") {
+        match &mut configuration.validator {
+            Some(ValidatorConfiguration::Team(static_config, _)) => static_config.static_keys = Some(discover_static_keys_str()),
+            Some(ValidatorConfiguration::MultiTeam(_, _)) => return Err(opaque_err!("STATIC_KEYS is not supported with multiple teams")),
+            None => return Err(opaque_err!("STATIC_KEYS requires TEAM_NAME or validator.teams")),
+        }
+    }
+
+    if let (true, Some(validator)) = (is_env_set("ENABLE_PROXY_DISCOVERY"), &mut configuration.validator) {
+        validator.common_mut().proxy_discovery = discover_enable_proxy_discovery();
+    }
+
+    if is_env_set("LISTENER") {
+        configuration.listener = discover_listener_str();
+    }
+
+    if is_env_set("SYNC_SCHEDULE") {
+        configuration.sync_schedule = discover_sync_schedule_str();
+    }
+
+    if is_env_set("NBF_VALIDATION") {
+        configuration.nbf_validation = discover_nbf_validation()?;
+    }
+
+    if is_env_set("EXP_VALIDATION") {
+        configuration.exp_validation = discover_exp_validation()?;
+    }
+
+    // The file may leave the validator to TEAM_NAME or TEAM_NAMES
+    configuration.get_validator()?;
+
+    Ok(configuration)
+}
+
+/// Parses an allowlist in the form `common_name=host[/path],...;...`
+fn discover_service_allowlist(allowlist_str: &str) -> types::StdResult<HashMap<String, Vec<ServiceGrant>>> {
+    let mut allowlist: HashMap<String, Vec<ServiceGrant>> = HashMap::new();
+
+    for entry_str in allowlist_str.split(";").filter(|s| !s.trim().is_empty()) {
+        let (common_name, grants_str) = entry_str
+            .split_once("=")
+            .ok_or(format!("service allowlist entry '{entry_str}' is missing '='"))?;
+        let grants = allowlist.entry(common_name.trim().to_string()).or_default();
+
+        for grant_str in grants_str.split(",").filter(|s| !s.trim().is_empty()) {
+            grants.push(ServiceGrant::from_str(grant_str)?);
+        }
+    }
+
+    Ok(allowlist)
+}
+
+/// Builds the bootstrap configuration from the configuration file section,
+/// if any, with environment variables taking precedence over file values.
+pub fn discover_bootstrap_configuration(file_configuration: Option<Configuration>) -> types::StdResult<Configuration> {
+    let mut configuration = match file_configuration {
+        Some(configuration) => apply_validator_overrides(configuration)?,
+        None => discover_validator_configuration()?,
+    };
+
+    apply_tls_overrides(&mut configuration.tls)?;
+    apply_denied_response_overrides(&mut configuration.denied_response)?;
+    apply_health_overrides(&mut configuration.health)?;
+    apply_cache_overrides(&mut configuration.cache)?;
+    apply_key_sync_overrides(&mut configuration.key_sync)?;
+    apply_identity_overrides(&mut configuration.identity)?;
+    apply_audit_overrides(&mut configuration.audit)?;
+    apply_rate_limit_overrides(&mut configuration.rate_limit)?;
+    apply_token_source_overrides(&mut configuration.token_sources)?;
+    apply_header_overrides(&mut configuration.headers)?;
+
+    if let Some(leeway_str) = non_empty(discover_time_leeway_str()) {
+        configuration.leeway = parse_seconds(&leeway_str, "TIME_LEEWAY")?;
+    }
+
+    if let Some(max_age_str) = non_empty(discover_max_token_age_str()) {
+        configuration.max_token_age = Some(parse_seconds(&max_age_str, "MAX_TOKEN_AGE")?);
+    }
+
+    if let Some(timeout_str) = non_empty(discover_shutdown_timeout_str()) {
+        configuration.shutdown_timeout = parse_seconds(&timeout_str, "SHUTDOWN_TIMEOUT")?;
+    }
+
+    if is_env_set("REMOVE_TOKEN_HEADER") {
+        configuration.remove_token_header = discover_remove_token_header();
+    }
+
+    if let Some(metrics_listener) = non_empty(discover_metrics_listener_str()) {
+        configuration.metrics_listener = Some(metrics_listener);
+    }
+
+    if let Some(rules_str) = non_empty(discover_bypass_rules_str()) {
+        configuration.bypass = rules_str
+            .split(";")
+            .filter(|s| !s.trim().is_empty())
+            .map(BypassRule::from_str)
+            .collect::<types::StdResult<_>>()?;
+    }
+
+    if let Some(allowlist_str) = non_empty(discover_service_allowlist_str()) {
+        configuration.service_allowlist = discover_service_allowlist(&allowlist_str)?;
+    }
+
+    if let Some(forward_auth_listener) = non_empty(discover_forward_auth_listener_str()) {
+        configuration.forward_auth_listener = Some(forward_auth_listener);
+    }
+
+    if let Some(headers_str) = non_empty(discover_forward_auth_headers_str()) {
+        configuration.forward_auth_headers =
+            ForwardAuthHeaders::from_str(&headers_str).map_err(|e| format!("invalid FORWARD_AUTH_HEADERS: {e}"))?;
+    }
+
+    // Forward auth proxies only copy headers from the answer, so cannot remove the token
+    if configuration.forward_auth_listener.is_some() && configuration.remove_token_header {
+        return Err(opaque_err!("REMOVE_TOKEN_HEADER is not supported with FORWARD_AUTH_LISTENER"));
+    }
+
+    Ok(configuration)
+}
+
+fn discover_validator_configuration() -> types::StdResult<Configuration> {
     let static_key_str = discover_static_keys_str();
-    let team_name = env::var("TEAM_NAME")?;
-    let nbf_validation = TimeConstraintMode::from_str(&discover_iat_validation_str())?;
-    let exp_validation = TimeConstraintMode::from_str(&discover_exp_validation_str())?;
+    let team_names_str = discover_team_names_str();
+    let nbf_validation = discover_nbf_validation()?;
+    let exp_validation = discover_exp_validation()?;
+
+    if !team_names_str.is_empty() {
+        if !static_key_str.is_empty() {
+            return Err(opaque_err!("STATIC_KEYS is not supported with TEAM_NAMES"));
+        }
+
+        let team_names: Vec<String> = team_names_str.split(",").map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+
+        if team_names.is_empty() {
+            return Err(opaque_err!("TEAM_NAMES does not contain any team names"));
+        }
+
+        return Ok(Configuration::new_multi_team_configuration(
+            &discover_listener_str(),
+            team_names,
+            &discover_sync_schedule_str(),
+            nbf_validation,
+            exp_validation,
+            discover_enable_proxy_discovery(),
+        ));
+    }
+
+    let team_name = env::var("TEAM_NAME").map_err(|e| format!("invalid TEAM_NAME: {e}"))?;
     let mut static_keys: Option<String> = None;
 
     if !static_key_str.is_empty() {
@@ -1,12 +1,33 @@
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use envoy_types::ext_authz::v3::pb::HttpStatusCode;
 use jnt::sockets::Listener;
 use jnt::{opaque_err, types};
-use rust_cfzt_validator::Validator;
+use serde::{Deserialize, Deserializer};
 
-#[derive(Debug, PartialEq)]
+use crate::server::audit::AuditSink;
+use crate::server::cache::DecisionCache;
+use crate::server::identity::IdentityEnricher;
+use crate::server::metrics::Metrics;
+use crate::server::ratelimit::RateLimiter;
+use crate::server::request::RequestTarget;
+use crate::server::validator::{new_agent, TeamSetValidator};
+use crate::tls::ReloadableTlsConfig;
+
+/// How a time based claim is checked. `Strict` keeps the validation library's
+/// defaults, checking `exp` with 60s of clock skew and leaving `nbf` unchecked.
+/// `Exact` checks the claim with no skew, `Leeway` allows the configured skew
+/// and `Lax` skips the check.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum TimeConstraintMode {
+    #[default]
     Strict,
+    Exact,
+    Leeway,
     Lax,
 }
 
@@ -16,16 +37,136 @@ impl FromStr for TimeConstraintMode {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "strict" => Ok(Self::Strict),
+            "exact" => Ok(Self::Exact),
+            "leeway" => Ok(Self::Leeway),
             "lax" => Ok(Self::Lax),
             _ => Err(opaque_err!("invalid time constraint value")),
         }
     }
 }
 
+/// A location in the client request that a token may be read from
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenSource {
+    Header,
+    Cookie,
+    Bearer,
+}
+
+impl TokenSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Header => "header",
+            Self::Cookie => "cookie",
+            Self::Bearer => "bearer",
+        }
+    }
+}
+
+impl FromStr for TokenSource {
+    type Err = Box<jnt::errors::OpaqueError>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "header" => Ok(Self::Header),
+            "cookie" => Ok(Self::Cookie),
+            "bearer" => Ok(Self::Bearer),
+            _ => Err(opaque_err!("invalid token source value")),
+        }
+    }
+}
+
+/// The client identity that failed validations are counted against
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+    #[default]
+    Source,
+    XForwardedFor,
+    CfConnectingIp,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = Box<jnt::errors::OpaqueError>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "source" => Ok(Self::Source),
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "cf-connecting-ip" => Ok(Self::CfConnectingIp),
+            _ => Err(opaque_err!("invalid rate limit key value")),
+        }
+    }
+}
+
+/// The headers a forward auth proxy describes the original request with. Traefik and
+/// Caddy send `X-Forwarded-Method`, `X-Forwarded-Host` and `X-Forwarded-Uri`, while
+/// nginx is configured to send `X-Original-Method` and `X-Original-URI` with the `Host`.
+/// Only the configured set is read, as proxies pass the other set on from the client.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardAuthHeaders {
+    #[default]
+    Forwarded,
+    Original,
+}
+
+impl FromStr for ForwardAuthHeaders {
+    type Err = Box<jnt::errors::OpaqueError>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "forwarded" => Ok(Self::Forwarded),
+            "original" => Ok(Self::Original),
+            _ => Err(opaque_err!("invalid forward auth headers value")),
+        }
+    }
+}
+
+fn default_token_sources() -> Vec<TokenSource> {
+    vec![TokenSource::Header]
+}
+
+struct HttpStatusVisitor;
+
+impl serde::de::Visitor<'_> for HttpStatusVisitor {
+    type Value = HttpStatusCode;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an HTTP status code")
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, code: u64) -> Result<Self::Value, E> {
+        i32::try_from(code)
+            .ok()
+            .and_then(|code| HttpStatusCode::try_from(code).ok())
+            .ok_or_else(|| E::custom(format!("unsupported HTTP status: {code}")))
+    }
+}
+
+fn deserialize_http_status<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HttpStatusCode, D::Error> {
+    deserializer.deserialize_u64(HttpStatusVisitor)
+}
+
+fn deserialize_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+}
+
+fn deserialize_optional_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+fn deserialize_headers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    Ok(BTreeMap::<String, String>::deserialize(deserializer)?.into_iter().collect())
+}
+
 pub struct CommonValidatorConfiguration {
     pub proxy_discovery: bool
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StaticTeamValidatorConfiguration {
     pub team_name: String,
     pub static_keys: Option<String>,
@@ -37,30 +178,608 @@ impl StaticTeamValidatorConfiguration {
     }
 }
 
+pub struct MultiTeamValidatorConfiguration {
+    pub teams: Vec<StaticTeamValidatorConfiguration>,
+}
+
+impl MultiTeamValidatorConfiguration {
+    pub fn is_static_keys(&self) -> bool {
+        self.teams.iter().all(|team| team.is_static_keys())
+    }
+}
+
+/// The validator section of a configuration file, listing teams explicitly.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ValidatorFileConfiguration {
+    teams: Vec<StaticTeamValidatorConfiguration>,
+    #[serde(default)]
+    proxy_discovery: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(try_from = "ValidatorFileConfiguration")]
 pub enum ValidatorConfiguration {
     Team(StaticTeamValidatorConfiguration, CommonValidatorConfiguration),
+    MultiTeam(MultiTeamValidatorConfiguration, CommonValidatorConfiguration),
+}
+
+impl TryFrom<ValidatorFileConfiguration> for ValidatorConfiguration {
+    type Error = String;
+
+    fn try_from(file_config: ValidatorFileConfiguration) -> Result<Self, Self::Error> {
+        let common_config = CommonValidatorConfiguration {
+            proxy_discovery: file_config.proxy_discovery,
+        };
+        let mut teams = file_config.teams;
+
+        match teams.len() {
+            0 => Err("validator.teams must list at least one team".to_string()),
+            1 => Ok(Self::Team(teams.remove(0), common_config)),
+            _ => Ok(Self::MultiTeam(MultiTeamValidatorConfiguration { teams }, common_config)),
+        }
+    }
 }
 
 impl ValidatorConfiguration {
-    pub fn get_default_team_name(&self) -> String {
+    pub fn common(&self) -> &CommonValidatorConfiguration {
+        match self {
+            Self::Team(_, common_config) => common_config,
+            Self::MultiTeam(_, common_config) => common_config,
+        }
+    }
+
+    pub fn common_mut(&mut self) -> &mut CommonValidatorConfiguration {
         match self {
-            Self::Team(config, _) => config.team_name.to_string(),
+            Self::Team(_, common_config) => common_config,
+            Self::MultiTeam(_, common_config) => common_config,
+        }
+    }
+
+    pub fn get_team_names(&self) -> Vec<String> {
+        match self {
+            Self::Team(config, _) => vec![config.team_name.to_string()],
+            Self::MultiTeam(config, _) => config
+                .teams
+                .iter()
+                .map(|team| team.team_name.to_string())
+                .collect(),
         }
     }
 
     pub fn requires_refresh(&self) -> bool {
         match self {
             Self::Team(config, _) => !config.is_static_keys(),
+            Self::MultiTeam(config, _) => !config.is_static_keys(),
+        }
+    }
+}
+
+pub fn parse_http_status(s: &str) -> types::StdResult<HttpStatusCode> {
+    let code: i32 = s.trim().parse()?;
+    Ok(HttpStatusCode::try_from(code).map_err(|_| format!("unsupported HTTP status: {code}"))?)
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeniedResponseConfiguration {
+    #[serde(deserialize_with = "deserialize_http_status")]
+    pub unauthenticated_status: HttpStatusCode,
+    #[serde(deserialize_with = "deserialize_http_status")]
+    pub forbidden_status: HttpStatusCode,
+    #[serde(deserialize_with = "deserialize_http_status")]
+    pub rate_limited_status: HttpStatusCode,
+    pub body: Option<String>,
+    #[serde(deserialize_with = "deserialize_headers")]
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for DeniedResponseConfiguration {
+    fn default() -> Self {
+        DeniedResponseConfiguration {
+            unauthenticated_status: HttpStatusCode::Unauthorized,
+            forbidden_status: HttpStatusCode::Forbidden,
+            rate_limited_status: HttpStatusCode::TooManyRequests,
+            body: None,
+            headers: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfiguration {
+    pub check_schedule: String,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub sync_failure_threshold: Duration,
+}
+
+impl Default for HealthConfiguration {
+    fn default() -> Self {
+        HealthConfiguration {
+            check_schedule: "*/10 * * * * *".to_string(),
+            sync_failure_threshold: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfiguration {
+    pub max_entries: usize,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub max_ttl: Duration,
+}
+
+impl Default for CacheConfiguration {
+    fn default() -> Self {
+        CacheConfiguration {
+            max_entries: 10000,
+            max_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The file form of a bypass rule, checked before use so that a rule
+/// can never bypass validation for every request.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BypassRuleFileConfiguration {
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+/// Lets matching requests through without a token. Every populated condition must match,
+/// and any value within a condition may match. Hosts starting with `*.` match subdomains,
+/// and paths ending in `*` match by prefix, otherwise both are matched exactly. Paths are
+/// matched after dot segments and duplicate slashes are resolved.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "BypassRuleFileConfiguration")]
+pub struct BypassRule {
+    pub methods: Vec<String>,
+    pub hosts: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+impl TryFrom<BypassRuleFileConfiguration> for BypassRule {
+    type Error = String;
+
+    fn try_from(file_config: BypassRuleFileConfiguration) -> Result<Self, Self::Error> {
+        if file_config.methods.is_empty() && file_config.hosts.is_empty() && file_config.paths.is_empty() {
+            return Err("bypass rules must restrict at least one of methods, hosts or paths".to_string());
+        }
+
+        Ok(BypassRule {
+            methods: file_config.methods,
+            hosts: file_config.hosts,
+            paths: file_config.paths,
+        })
+    }
+}
+
+/// Recognised so that a rule listing only methods is not taken for a host
+const HTTP_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// Splits a target in the form `host[/path]`
+fn split_target(target: &str) -> (&str, &str) {
+    match target.find('/') {
+        Some(index) => target.split_at(index),
+        None => (target, ""),
+    }
+}
+
+fn split_values(values: &str) -> Vec<String> {
+    values
+        .split(',')
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+impl FromStr for BypassRule {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parses a rule in the form `[METHOD,...] host[/path]` or `METHOD,...`, where a host
+    /// of `*` matches any host
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (methods_str, target) = match s.trim().split_once(char::is_whitespace) {
+            Some((methods_str, target)) => (methods_str, target.trim()),
+            None if split_values(s).iter().all(|value| HTTP_METHODS.iter().any(|method| method.eq_ignore_ascii_case(value))) => (s.trim(), ""),
+            None => ("", s.trim()),
+        };
+        let (host, path) = split_target(target);
+
+        Ok(BypassRule::try_from(BypassRuleFileConfiguration {
+            methods: split_values(methods_str),
+            hosts: split_values(host).into_iter().filter(|host| host != "*").collect(),
+            paths: split_values(path),
+        })
+        .map_err(|e| format!("invalid bypass rule '{s}': {e}"))?)
+    }
+}
+
+fn matches_host(pattern: &str, authority: &str) -> bool {
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => authority,
+    };
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len() > domain.len()
+            && host.to_lowercase().ends_with(&format!(".{}", domain.to_lowercase())),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn matches_path(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => pattern == path,
+    }
+}
+
+fn matches_hosts_and_paths(hosts: &[String], paths: &[String], target: &RequestTarget) -> bool {
+    (hosts.is_empty() || hosts.iter().any(|host| matches_host(host, &target.authority)))
+        && (paths.is_empty() || paths.iter().any(|path| matches_path(path, &target.path)))
+}
+
+impl BypassRule {
+    pub fn matches(&self, target: &RequestTarget) -> bool {
+        (self.methods.is_empty() || self.methods.iter().any(|method| method.eq_ignore_ascii_case(&target.method)))
+            && matches_hosts_and_paths(&self.hosts, &self.paths, target)
+    }
+}
+
+/// Hosts and paths a service token may reach, matched like bypass rules.
+/// An empty list of hosts or paths matches any.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceGrant {
+    pub hosts: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+impl FromStr for ServiceGrant {
+    type Err = Box<dyn std::error::Error>;
+
+    /// Parses a grant in the form `host[/path]`, where a host of `*` matches any host
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, path) = split_target(s.trim());
+
+        if host.is_empty() {
+            return Err(format!("service grant '{s}' has no host").into());
+        }
+
+        Ok(ServiceGrant {
+            hosts: split_values(host).into_iter().filter(|host| host != "*").collect(),
+            paths: split_values(path),
+        })
+    }
+}
+
+impl ServiceGrant {
+    pub fn matches(&self, target: &RequestTarget) -> bool {
+        matches_hosts_and_paths(&self.hosts, &self.paths, target)
+    }
+}
+
+/// A token bucket per client, drained by failed validations. Clients with an empty
+/// bucket are denied before their token is verified. Header keys fall back to the
+/// source address when the header is missing. X-Forwarded-For is read `trusted_hops`
+/// entries from the right, so only addresses appended by trusted proxies are used.
+/// Refilled buckets are forgotten on the sweep schedule.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfiguration {
+    pub enabled: bool,
+    pub key: RateLimitKey,
+    pub burst: u32,
+    pub refill_per_second: f64,
+    pub max_sources: usize,
+    pub trusted_hops: usize,
+    pub sweep_schedule: String,
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        RateLimitConfiguration {
+            enabled: false,
+            key: RateLimitKey::default(),
+            burst: 20,
+            refill_per_second: 1.0,
+            max_sources: 100000,
+            trusted_hops: 1,
+            sweep_schedule: "*/10 * * * * *".to_string(),
+        }
+    }
+}
+
+/// Retry behaviour of the scheduled key synchronisation, the minimum interval
+/// between on-demand refreshes triggered by unknown key ids, and the timeout
+/// of each key fetch.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeySyncConfiguration {
+    pub retry_attempts: u32,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub retry_backoff: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub retry_max_backoff: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub refresh_interval: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub timeout: Duration,
+}
+
+impl Default for KeySyncConfiguration {
+    fn default() -> Self {
+        KeySyncConfiguration {
+            retry_attempts: 5,
+            retry_backoff: Duration::from_secs(5),
+            retry_max_backoff: Duration::from_secs(300),
+            refresh_interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Enrichment of user identities with groups from the Access get-identity endpoint.
+/// The base URL defaults to the token issuer.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfiguration {
+    pub enabled: bool,
+    pub base_url: Option<String>,
+    pub max_entries: usize,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub timeout: Duration,
+}
+
+impl Default for IdentityConfiguration {
+    fn default() -> Self {
+        IdentityConfiguration {
+            enabled: false,
+            base_url: None,
+            max_entries: 10000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The audit log of authorization decisions, written as JSON Lines to a
+/// size-rotated file or, when no file is set, to stdout.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfiguration {
+    pub enabled: bool,
+    pub file: Option<String>,
+    pub max_file_size: u64,
+    pub max_files: usize,
+    pub buffer_size: usize,
+}
+
+impl Default for AuditConfiguration {
+    fn default() -> Self {
+        AuditConfiguration {
+            enabled: false,
+            file: None,
+            max_file_size: 100 * 1024 * 1024,
+            max_files: 5,
+            buffer_size: 10000,
+        }
+    }
+}
+
+/// How custom claims are forwarded. `Flatten` adds a `Custom-{key}` header per claim,
+/// with nested keys joined by dots and arrays joined by the separator. `Base64` adds a
+/// single `Custom-Claims` header holding the base64 encoded JSON of every custom claim.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomClaimMode {
+    #[default]
+    Flatten,
+    Base64,
+}
+
+impl FromStr for CustomClaimMode {
+    type Err = Box<jnt::errors::OpaqueError>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "flatten" => Ok(Self::Flatten),
+            "base64" => Ok(Self::Base64),
+            _ => Err(opaque_err!("invalid custom claim mode value")),
+        }
+    }
+}
+
+/// Naming of the identity headers added to allowed requests. Each header name is
+/// the prefix followed by the field's default suffix (such as `Email`), unless it
+/// is renamed in `names` or left out through `omit`. Fields are matched case-insensitively.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderConfiguration {
+    pub prefix: String,
+    pub names: HashMap<String, String>,
+    pub omit: Vec<String>,
+    pub custom_claims: CustomClaimMode,
+    pub custom_claim_separator: String,
+}
+
+impl Default for HeaderConfiguration {
+    fn default() -> Self {
+        HeaderConfiguration {
+            prefix: "X-Cfzt-Extauthz-".to_string(),
+            names: HashMap::new(),
+            omit: vec![],
+            custom_claims: CustomClaimMode::default(),
+            custom_claim_separator: ",".to_string(),
         }
     }
 }
 
+/// The fixed fields emitted as identity headers. Custom claim headers are
+/// named after the claims themselves, so cannot be listed up front.
+const IDENTITY_HEADER_FIELDS: &[&str] = &[
+    "Token-Type", "Token-Source", "Audiences", "Email", "Expiry", "Issued-At", "Not-Before", "Issuer", "Type",
+    "Nonce", "Subject", "Country", "Common-Name", "Groups", "Group-Ids", "Idp-Id", "Idp-Type", "Custom-Claims",
+];
+
+/// Whether a field can be renamed or omitted, being a fixed field or a custom claim header
+fn is_header_field(field: &str) -> bool {
+    let is_custom_claim = field.get(..7).is_some_and(|prefix| prefix.eq_ignore_ascii_case("Custom-")) && field.len() > 7;
+    is_custom_claim || IDENTITY_HEADER_FIELDS.iter().any(|known| known.eq_ignore_ascii_case(field))
+}
+
+impl HeaderConfiguration {
+    /// Rejects renamed or omitted fields that are never emitted, which are most
+    /// likely misspelt and would otherwise be ignored
+    pub fn validate(&self) -> types::EmptyResult {
+        let names = self.names.keys().map(|field| ("names", field));
+        let omit = self.omit.iter().map(|field| ("omit", field));
+
+        for (setting, field) in names.chain(omit) {
+            if !is_header_field(field) {
+                return Err(format!("unknown header field '{field}' in headers.{setting}").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The names of every fixed identity header that can be emitted
+    pub fn get_identity_header_names(&self) -> Vec<String> {
+        IDENTITY_HEADER_FIELDS.iter().filter_map(|field| self.get_header_name(field)).collect()
+    }
+
+    pub fn get_header_name(&self, field: &str) -> Option<String> {
+        if self.omit.iter().any(|omitted| omitted.eq_ignore_ascii_case(field)) {
+            return None;
+        }
+
+        let suffix = self
+            .names
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(field))
+            .map_or(field, |(_, rename)| rename.as_str());
+
+        Some(format!("{}{suffix}", self.prefix))
+    }
+
+    /// Whether a client header could collide with an identity header, being under
+    /// the prefix, the name of a fixed or renamed field, or a custom claim header.
+    pub fn is_identity_header(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let prefix = self.prefix.to_lowercase();
+
+        if (!prefix.is_empty() && name.starts_with(&prefix)) || name.starts_with(&format!("{prefix}custom-")) {
+            return true;
+        }
+
+        self.get_identity_header_names().iter().any(|emitted| emitted.eq_ignore_ascii_case(&name))
+            || self.names.values().any(|rename| format!("{}{rename}", self.prefix).eq_ignore_ascii_case(&name))
+    }
+}
+
+fn default_listener() -> String {
+    "tcp://[::1]:10000".to_string()
+}
+
+fn default_sync_schedule() -> String {
+    "0 0 0 * * *".to_string()
+}
+
+fn default_tls_reload_schedule() -> String {
+    "0 * * * * *".to_string()
+}
+
+/// Serves the listener over TLS, optionally requiring client certificates
+/// signed by the CA bundle. The files are checked for changes on the reload schedule.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfiguration {
+    pub cert_file: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+    #[serde(default = "default_tls_reload_schedule")]
+    pub reload_schedule: String,
+}
+
+impl TlsConfiguration {
+    pub fn new(cert_file: &str, key_file: &str) -> Self {
+        TlsConfiguration {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            client_ca_file: None,
+            reload_schedule: default_tls_reload_schedule(),
+        }
+    }
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_leeway() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Configuration {
+    #[serde(default = "default_listener")]
     pub listener: String,
-    pub validator: ValidatorConfiguration,
+    #[serde(default)]
+    pub validator: Option<ValidatorConfiguration>,
+    #[serde(default)]
+    pub tls: Option<TlsConfiguration>,
+    #[serde(default = "default_sync_schedule")]
     pub sync_schedule: String,
+    #[serde(default)]
+    pub key_sync: KeySyncConfiguration,
+    #[serde(default)]
     pub nbf_validation: TimeConstraintMode,
+    #[serde(default)]
     pub exp_validation: TimeConstraintMode,
+    #[serde(default = "default_leeway", deserialize_with = "deserialize_seconds")]
+    pub leeway: Duration,
+    #[serde(default, deserialize_with = "deserialize_optional_seconds")]
+    pub max_token_age: Option<Duration>,
+    #[serde(default)]
+    pub denied_response: DeniedResponseConfiguration,
+    #[serde(default)]
+    pub health: HealthConfiguration,
+    #[serde(default)]
+    pub metrics_listener: Option<String>,
+    #[serde(default)]
+    pub forward_auth_listener: Option<String>,
+    #[serde(default)]
+    pub forward_auth_headers: ForwardAuthHeaders,
+    #[serde(default = "default_shutdown_timeout", deserialize_with = "deserialize_seconds")]
+    pub shutdown_timeout: Duration,
+    #[serde(default)]
+    pub cache: CacheConfiguration,
+    #[serde(default)]
+    pub identity: IdentityConfiguration,
+    #[serde(default)]
+    pub audit: AuditConfiguration,
+    #[serde(default)]
+    pub bypass: Vec<BypassRule>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfiguration,
+    #[serde(default)]
+    pub service_allowlist: HashMap<String, Vec<ServiceGrant>>,
+    #[serde(default = "default_token_sources")]
+    pub token_sources: Vec<TokenSource>,
+    #[serde(default)]
+    pub remove_token_header: bool,
+    #[serde(default)]
+    pub headers: HeaderConfiguration,
 }
 
 impl Configuration {
@@ -73,10 +792,29 @@ impl Configuration {
     ) -> Self {
         Configuration {
             listener: listener.to_string(),
-            validator: validator_config,
+            validator: Some(validator_config),
+            tls: None,
             sync_schedule: sync_schedule.to_string(),
+            key_sync: KeySyncConfiguration::default(),
             nbf_validation,
             exp_validation,
+            leeway: default_leeway(),
+            max_token_age: None,
+            denied_response: DeniedResponseConfiguration::default(),
+            health: HealthConfiguration::default(),
+            metrics_listener: None,
+            forward_auth_listener: None,
+            forward_auth_headers: ForwardAuthHeaders::default(),
+            shutdown_timeout: default_shutdown_timeout(),
+            cache: CacheConfiguration::default(),
+            identity: IdentityConfiguration::default(),
+            audit: AuditConfiguration::default(),
+            bypass: vec![],
+            rate_limit: RateLimitConfiguration::default(),
+            service_allowlist: HashMap::new(),
+            token_sources: default_token_sources(),
+            remove_token_header: false,
+            headers: HeaderConfiguration::default(),
         }
     }
 
@@ -97,7 +835,7 @@ impl Configuration {
                     static_keys,
                 },
                 CommonValidatorConfiguration {
-                    proxy_discovery: proxy_discovery,
+                    proxy_discovery,
                 },
             ),
             sync_schedule,
@@ -106,11 +844,248 @@ impl Configuration {
         )
     }
 
+    pub fn new_multi_team_configuration(
+        listener: &str,
+        team_names: Vec<String>,
+        sync_schedule: &str,
+        nbf_validation: TimeConstraintMode,
+        exp_validation: TimeConstraintMode,
+        proxy_discovery: bool,
+    ) -> Self {
+        Configuration::new(
+            listener,
+            ValidatorConfiguration::MultiTeam(
+                MultiTeamValidatorConfiguration {
+                    teams: team_names
+                        .iter()
+                        .map(|team_name| StaticTeamValidatorConfiguration {
+                            team_name: team_name.to_string(),
+                            static_keys: None,
+                        })
+                        .collect(),
+                },
+                CommonValidatorConfiguration { proxy_discovery },
+            ),
+            sync_schedule,
+            nbf_validation,
+            exp_validation,
+        )
+    }
+
+    /// The validator configuration, which is required once environment
+    /// overrides have been applied
+    pub fn get_validator(&self) -> types::StdResult<&ValidatorConfiguration> {
+        Ok(self.validator.as_ref().ok_or("validator, TEAM_NAME or TEAM_NAMES must be set")?)
+    }
+
     pub fn open_listener(&self) -> types::StdResult<Listener> {
         Listener::from_url(url::Url::parse(&self.listener)?)
     }
 
-    pub fn new_validator(&self) -> types::StdResult<Box<dyn Validator>> {
-        crate::server::validator::new_validator(&self.validator)
+    pub fn new_tls_config(&self) -> types::StdResult<Option<ReloadableTlsConfig>> {
+        match &self.tls {
+            Some(tls) => Ok(Some(ReloadableTlsConfig::new(tls.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn open_metrics_listener(&self) -> types::StdResult<Option<Listener>> {
+        match &self.metrics_listener {
+            Some(listener) => Ok(Some(Listener::from_url(url::Url::parse(listener)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn open_forward_auth_listener(&self) -> types::StdResult<Option<Listener>> {
+        match &self.forward_auth_listener {
+            Some(listener) => Ok(Some(Listener::from_url(url::Url::parse(listener)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn new_decision_cache(&self) -> Option<DecisionCache> {
+        let cache = DecisionCache::new(self.cache.max_entries, self.cache.max_ttl);
+
+        match cache.is_enabled() {
+            true => Some(cache),
+            false => None,
+        }
+    }
+
+    pub fn new_identity_enricher(&self) -> types::StdResult<Option<IdentityEnricher>> {
+        if !self.identity.enabled {
+            return Ok(None);
+        }
+
+        Ok(Some(IdentityEnricher::new(
+            new_agent(self.get_validator()?.common(), Some(self.identity.timeout)),
+            self.identity.base_url.clone(),
+            self.identity.max_entries,
+        )))
+    }
+
+    pub fn new_rate_limiter(&self) -> Option<RateLimiter> {
+        match self.rate_limit.enabled {
+            true => Some(RateLimiter::new(&self.rate_limit)),
+            false => None,
+        }
+    }
+
+    pub fn new_audit_sink(&self, metrics: Arc<Metrics>) -> types::StdResult<Option<AuditSink>> {
+        match self.audit.enabled {
+            true => Ok(Some(AuditSink::new(&self.audit, metrics)?)),
+            false => Ok(None),
+        }
+    }
+
+    pub fn new_validator(&self) -> types::StdResult<TeamSetValidator> {
+        crate::server::validator::new_validator(self.get_validator()?, self.key_sync.refresh_interval, self.key_sync.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_target;
+
+    #[test]
+    fn validates_renamed_and_omitted_header_fields() {
+        let headers = HeaderConfiguration {
+            names: HashMap::from([("email".to_string(), "User".to_string()), ("Custom-groups".to_string(), "Groups".to_string())]),
+            omit: vec!["NONCE".to_string(), "custom-department".to_string()],
+            ..Default::default()
+        };
+        assert!(headers.validate().is_ok());
+
+        let headers = HeaderConfiguration { names: HashMap::from([("Emial".to_string(), "User".to_string())]), ..Default::default() };
+        assert_eq!(headers.validate().unwrap_err().to_string(), "unknown header field 'Emial' in headers.names");
+
+        let headers = HeaderConfiguration { omit: vec!["Custom-".to_string()], ..Default::default() };
+        assert_eq!(headers.validate().unwrap_err().to_string(), "unknown header field 'Custom-' in headers.omit");
+    }
+
+    #[test]
+    fn parses_bypass_rules() {
+        let rule = BypassRule::from_str("GET,HEAD *.example.com/public/*").unwrap();
+        assert_eq!(rule.methods, vec!["GET", "HEAD"]);
+        assert_eq!(rule.hosts, vec!["*.example.com"]);
+        assert_eq!(rule.paths, vec!["/public/*"]);
+
+        let rule = BypassRule::from_str("*/healthz").unwrap();
+        assert!(rule.methods.is_empty() && rule.hosts.is_empty());
+        assert_eq!(rule.paths, vec!["/healthz"]);
+
+        let rule = BypassRule::from_str(" OPTIONS ").unwrap();
+        assert_eq!(rule.methods, vec!["OPTIONS"]);
+        assert!(rule.hosts.is_empty() && rule.paths.is_empty());
+
+        let rule = BypassRule::from_str("status.example.com").unwrap();
+        assert!(rule.methods.is_empty());
+        assert_eq!(rule.hosts, vec!["status.example.com"]);
+    }
+
+    #[test]
+    fn parses_without_a_validator() {
+        let configuration: Configuration = serde_yaml_ng::from_str("listener: tcp://127.0.0.1:9000").unwrap();
+        assert!(configuration.validator.is_none());
+        assert!(configuration.get_validator().is_err());
+    }
+
+    #[test]
+    fn parses_method_only_bypass_rules_in_any_case() {
+        for (s, methods) in [("options", vec!["options"]), ("Get,hEaD", vec!["Get", "hEaD"])] {
+            let rule = BypassRule::from_str(s).unwrap();
+            assert_eq!(rule.methods, methods);
+            assert!(rule.hosts.is_empty() && rule.paths.is_empty());
+        }
+
+        let rule = BypassRule::from_str("options").unwrap();
+        assert!(rule.matches(&new_target("OPTIONS", "any.example.com", "/admin")));
+        assert!(!rule.matches(&new_target("GET", "any.example.com", "/admin")));
+    }
+
+    #[test]
+    fn rejects_unrestricted_bypass_rules() {
+        assert!(BypassRule::from_str("*").is_err());
+        assert!(BypassRule::from_str("").is_err());
+        assert!(serde_yaml_ng::from_str::<BypassRule>("{}").is_err());
+    }
+
+    #[test]
+    fn matches_bypass_rules() {
+        let rule = BypassRule::from_str("GET *.example.com/public/*").unwrap();
+
+        assert!(rule.matches(&new_target("get", "app.example.com:443", "/public/site.css")));
+        assert!(!rule.matches(&new_target("POST", "app.example.com", "/public/site.css")));
+        assert!(!rule.matches(&new_target("GET", "example.com", "/public/site.css")));
+        assert!(!rule.matches(&new_target("GET", "app.example.com", "/private")));
+        assert!(!rule.matches(&new_target("GET", "app.example.com", "/public/../admin")));
+        assert!(!rule.matches(&new_target("GET", "app.example.com", "/public/%2e%2e/admin")));
+
+        let rule = BypassRule::from_str("*/healthz").unwrap();
+
+        assert!(rule.matches(&new_target("GET", "any.example.com", "//healthz")));
+        assert!(!rule.matches(&new_target("GET", "any.example.com", "/healthz/x")));
+        assert!(!rule.matches(&new_target("GET", "any.example.com", "/healthz/../admin")));
+
+        let rule = BypassRule::from_str("OPTIONS").unwrap();
+
+        assert!(rule.matches(&new_target("OPTIONS", "any.example.com", "/admin")));
+        assert!(!rule.matches(&new_target("GET", "any.example.com", "/admin")));
+    }
+
+    #[test]
+    fn parses_service_grants() {
+        let grant = ServiceGrant::from_str(" api.example.com/v1/* ").unwrap();
+        assert_eq!(grant.hosts, vec!["api.example.com"]);
+        assert_eq!(grant.paths, vec!["/v1/*"]);
+
+        let grant = ServiceGrant::from_str("*/metrics").unwrap();
+        assert!(grant.hosts.is_empty());
+        assert_eq!(grant.paths, vec!["/metrics"]);
+
+        let grant = ServiceGrant::from_str("*").unwrap();
+        assert!(grant.hosts.is_empty() && grant.paths.is_empty());
+
+        assert!(ServiceGrant::from_str("/v1").is_err());
+        assert!(ServiceGrant::from_str(" ").is_err());
+    }
+
+    #[test]
+    fn matches_service_grants() {
+        let grant = ServiceGrant::from_str("api.example.com/v1/*").unwrap();
+
+        assert!(grant.matches(&new_target("POST", "api.example.com:443", "/v1/jobs")));
+        assert!(!grant.matches(&new_target("POST", "app.example.com", "/v1/jobs")));
+        assert!(!grant.matches(&new_target("POST", "api.example.com", "/v2/jobs")));
+        assert!(!grant.matches(&new_target("POST", "api.example.com", "/v1/../admin")));
+
+        let grant = ServiceGrant::from_str("*").unwrap();
+
+        assert!(grant.matches(&new_target("GET", "any.example.com", "/anything")));
+    }
+
+    #[test]
+    fn recognises_identity_headers_with_empty_prefix() {
+        let headers = HeaderConfiguration {
+            prefix: String::new(),
+            names: HashMap::from([("Subject".to_string(), "X-User".to_string())]),
+            ..HeaderConfiguration::default()
+        };
+
+        assert!(headers.is_identity_header("email"));
+        assert!(headers.is_identity_header("Token-Type"));
+        assert!(headers.is_identity_header("x-user"));
+        assert!(headers.is_identity_header("custom-team"));
+        assert!(!headers.is_identity_header("accept"));
+    }
+
+    #[test]
+    fn recognises_identity_headers_under_prefix() {
+        let headers = HeaderConfiguration::default();
+
+        assert!(headers.is_identity_header("x-cfzt-extauthz-email"));
+        assert!(headers.is_identity_header("X-Cfzt-Extauthz-Anything"));
+        assert!(!headers.is_identity_header("email"));
     }
 }
@@ -0,0 +1,112 @@
+use crate::config::file::schema::ConfigurationFile;
+use jnt::types;
+use std::fs;
+
+jnt::env!(discover_config_file_str, "CONFIG_FILE", "");
+
+pub fn discover_configuration_file() -> types::StdResult<ConfigurationFile> {
+    let path = discover_config_file_str();
+
+    if path.is_empty() {
+        return Ok(ConfigurationFile::default());
+    }
+
+    let configuration: ConfigurationFile = serde_yaml_ng::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| format!("invalid configuration file {path}: {e}"))?;
+
+    Ok(configuration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::bootstrap::discovery::discover_bootstrap_configuration;
+    use crate::testing::new_temp_dir;
+    use std::env;
+    use std::sync::{Mutex, PoisonError};
+    use std::time::Duration;
+
+    /// Serialises the tests, which share the process environment
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const CONFIGURATION: &str = r#"
+bootstrap:
+  listener: tcp://127.0.0.1:9000
+  leeway: 30
+  validator:
+    teams:
+      - team_name: example
+  rate_limit:
+    burst: 5
+    refill_per_second: 2
+"#;
+
+    fn discover_from_file(name: &str, yaml: &str) -> types::StdResult<ConfigurationFile> {
+        let path = new_temp_dir(name).join("config.yaml");
+        fs::write(&path, yaml).unwrap();
+
+        env::set_var("CONFIG_FILE", &path);
+        let result = discover_configuration_file();
+        env::remove_var("CONFIG_FILE");
+        result
+    }
+
+    #[test]
+    fn overrides_file_values_with_environment_variables() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let file_configuration = discover_from_file("overrides", CONFIGURATION).unwrap();
+
+        env::set_var("LISTENER", "tcp://127.0.0.1:9100");
+        env::set_var("RATE_LIMIT_BURST", "10");
+        let result = discover_bootstrap_configuration(file_configuration.bootstrap);
+        env::remove_var("LISTENER");
+        env::remove_var("RATE_LIMIT_BURST");
+
+        let configuration = result.unwrap();
+        assert_eq!(configuration.listener, "tcp://127.0.0.1:9100");
+        assert_eq!(configuration.rate_limit.burst, 10);
+        assert_eq!(configuration.rate_limit.refill_per_second, 2.0);
+        assert_eq!(configuration.leeway, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn names_the_invalid_field() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let yaml = CONFIGURATION.replace("burst: 5", "burst: many");
+
+        let error = discover_from_file("invalid", &yaml)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("bootstrap.rate_limit.burst"), "{error}");
+    }
+
+    #[test]
+    fn rejects_rate_limits_that_never_refill() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for (from, to, error) in [
+            (
+                "burst: 5",
+                "burst: 0",
+                "RATE_LIMIT_BURST must be at least 1",
+            ),
+            (
+                "refill_per_second: 2",
+                "refill_per_second: 0",
+                "RATE_LIMIT_REFILL must be greater than 0",
+            ),
+            (
+                "refill_per_second: 2",
+                "refill_per_second: -1",
+                "RATE_LIMIT_REFILL must be greater than 0",
+            ),
+        ] {
+            let yaml = CONFIGURATION.replace(from, to);
+            let file_configuration = discover_from_file("rate-limit", &yaml).unwrap();
+
+            let result = discover_bootstrap_configuration(file_configuration.bootstrap);
+            assert_eq!(result.err().unwrap().to_string(), error);
+        }
+    }
+}
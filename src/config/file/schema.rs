@@ -0,0 +1,14 @@
+use serde::Deserialize;
+
+use crate::config::audience::schema::AudienceConfiguration;
+use crate::config::bootstrap::schema::Configuration;
+use crate::config::policy::schema::PolicySet;
+
+/// A configuration file, with one optional section per configuration area.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigurationFile {
+    pub bootstrap: Option<Configuration>,
+    pub audience: AudienceConfiguration,
+    pub policy: Option<PolicySet>,
+}
@@ -1,2 +1,4 @@
 pub mod audience;
 pub mod bootstrap;
+pub mod file;
+pub mod policy;
@@ -0,0 +1,25 @@
+use crate::config::policy::schema::PolicySet;
+use jnt::types;
+use std::fs;
+
+jnt::env!(discover_policy_file_str, "POLICY_FILE", "");
+
+/// Loads the policy from POLICY_FILE if set, otherwise falls back to
+/// the policy section of the configuration file.
+pub fn discover_policy(file_policy: Option<PolicySet>) -> types::StdResult<Option<PolicySet>> {
+    let path = discover_policy_file_str();
+
+    let policy = if path.is_empty() {
+        file_policy
+    } else {
+        let policy: PolicySet = serde_yaml_ng::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| format!("invalid policy file {path}: {e}"))?;
+        Some(policy)
+    };
+
+    if let Some(policy) = &policy {
+        policy.validate()?;
+    }
+
+    Ok(policy)
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use jnt::{opaque_err, types};
+use serde::Deserialize;
+
+use crate::server::request::{
+    get_custom_claim_values, has_path_prefix, PrincipalAssertion, RequestTarget,
+};
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    #[default]
+    Deny,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PrincipalType {
+    User,
+    Service,
+}
+
+/// The conditions a request must meet for a rule to apply. Every
+/// populated condition must match, and any value within a condition may match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyMatch {
+    pub principal: Option<PrincipalType>,
+    pub email_domains: Vec<String>,
+    pub emails: Vec<String>,
+    pub countries: Vec<String>,
+    pub claims: HashMap<String, Vec<String>>,
+    pub common_names: Vec<String>,
+    pub methods: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub name: String,
+    pub action: PolicyAction,
+    #[serde(default, rename = "match")]
+    pub conditions: PolicyMatch,
+}
+
+/// An ordered list of rules, where the first matching rule decides
+/// the outcome and the default action applies when none match.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicySet {
+    #[serde(default)]
+    pub default: PolicyAction,
+    pub rules: Vec<PolicyRule>,
+}
+
+fn any_or_empty<T>(values: &[T], predicate: impl Fn(&T) -> bool) -> bool {
+    values.is_empty() || values.iter().any(predicate)
+}
+
+fn get_email_domain(email: &str) -> &str {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .unwrap_or("")
+}
+
+impl PolicyMatch {
+    fn has_user_conditions(&self) -> bool {
+        !self.emails.is_empty()
+            || !self.email_domains.is_empty()
+            || !self.countries.is_empty()
+            || !self.claims.is_empty()
+    }
+
+    /// Rejects conditions that could never match together, such as
+    /// a service principal with email conditions.
+    fn validate(&self) -> types::EmptyResult {
+        match self.principal {
+            Some(PrincipalType::Service) if self.has_user_conditions() => Err(opaque_err!(
+                "service principals cannot match emails, email domains, countries or claims"
+            )),
+            Some(PrincipalType::User) if !self.common_names.is_empty() => {
+                Err(opaque_err!("user principals cannot match common names"))
+            }
+            None if self.has_user_conditions() && !self.common_names.is_empty() => Err(
+                opaque_err!("common names cannot be combined with user conditions"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn matches_principal(&self, assertion: &PrincipalAssertion) -> bool {
+        match assertion {
+            PrincipalAssertion::User(user) => {
+                self.principal.unwrap_or(PrincipalType::User) == PrincipalType::User
+                    && self.common_names.is_empty()
+                    && any_or_empty(&self.emails, |email| {
+                        email.eq_ignore_ascii_case(&user.email)
+                    })
+                    && any_or_empty(&self.email_domains, |domain| {
+                        domain.eq_ignore_ascii_case(get_email_domain(&user.email))
+                    })
+                    && any_or_empty(&self.countries, |country| {
+                        country.eq_ignore_ascii_case(&user.country)
+                    })
+                    && self.claims.iter().all(|(claim, values)| {
+                        get_custom_claim_values(&user.custom, claim)
+                            .iter()
+                            .any(|actual| values.contains(actual))
+                    })
+            }
+            PrincipalAssertion::Service(service) => {
+                self.principal.unwrap_or(PrincipalType::Service) == PrincipalType::Service
+                    && self.emails.is_empty()
+                    && self.email_domains.is_empty()
+                    && self.countries.is_empty()
+                    && self.claims.is_empty()
+                    && any_or_empty(&self.common_names, |name| name == &service.common_name)
+            }
+        }
+    }
+
+    fn matches_target(&self, target: &RequestTarget) -> bool {
+        any_or_empty(&self.methods, |method| {
+            method.eq_ignore_ascii_case(&target.method)
+        }) && any_or_empty(&self.paths, |prefix| has_path_prefix(&target.path, prefix))
+    }
+
+    pub fn matches(&self, assertion: &PrincipalAssertion, target: &RequestTarget) -> bool {
+        self.matches_target(target) && self.matches_principal(assertion)
+    }
+}
+
+impl PolicySet {
+    /// Checks every rule can match, so a misconfigured rule fails at startup
+    /// instead of silently never applying.
+    pub fn validate(&self) -> types::EmptyResult {
+        for rule in &self.rules {
+            rule.conditions
+                .validate()
+                .map_err(|e| format!("invalid policy rule '{}': {e}", rule.name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates the rules in order, returning the decided action and
+    /// the name of the rule responsible, if any.
+    pub fn evaluate(
+        &self,
+        assertion: &PrincipalAssertion,
+        target: &RequestTarget,
+    ) -> (PolicyAction, Option<&str>) {
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.conditions.matches(assertion, target))
+        {
+            Some(rule) => (rule.action, Some(&rule.name)),
+            None => (self.default, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_target;
+    use serde_json::json;
+
+    const POLICY: &str = r#"
+default: deny
+rules:
+  - name: deny-contractors
+    action: deny
+    match:
+      email_domains: [contractor.example.com]
+  - name: admins
+    action: allow
+    match:
+      paths: [/admin]
+      claims:
+        org.role: [admin]
+  - name: readers
+    action: allow
+    match:
+      principal: user
+      methods: [GET]
+      countries: [au, nz]
+  - name: ci
+    action: allow
+    match:
+      common_names: [ci.example.com]
+      paths: [/deploy]
+"#;
+
+    fn new_user(email: &str, country: &str, custom: serde_json::Value) -> PrincipalAssertion {
+        PrincipalAssertion::from_claims_value(&json!({
+            "aud": ["aud1"],
+            "email": email,
+            "exp": 2,
+            "iat": 1,
+            "nbf": 1,
+            "iss": "https://team.cloudflareaccess.com",
+            "type": "app",
+            "identity_nonce": "nonce",
+            "sub": "user-1",
+            "country": country,
+            "custom": custom,
+        }))
+        .unwrap()
+    }
+
+    fn new_service(common_name: &str) -> PrincipalAssertion {
+        PrincipalAssertion::from_claims_value(&json!({
+            "aud": ["aud1"],
+            "exp": 2,
+            "iat": 1,
+            "iss": "https://team.cloudflareaccess.com",
+            "type": "app",
+            "sub": "",
+            "common_name": common_name,
+        }))
+        .unwrap()
+    }
+
+    fn evaluate(
+        assertion: &PrincipalAssertion,
+        method: &str,
+        path: &str,
+    ) -> (PolicyAction, Option<String>) {
+        let policy: PolicySet = serde_yaml_ng::from_str(POLICY).unwrap();
+        let (action, rule) =
+            policy.evaluate(assertion, &new_target(method, "app.example.com", path));
+        (action, rule.map(|rule| rule.to_string()))
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let contractor = new_user("a@contractor.example.com", "AU", json!({}));
+
+        assert_eq!(
+            evaluate(&contractor, "GET", "/"),
+            (PolicyAction::Deny, Some("deny-contractors".to_string()))
+        );
+    }
+
+    #[test]
+    fn matches_nested_and_array_claims() {
+        let admin = new_user(
+            "a@example.com",
+            "US",
+            json!({"org": {"role": ["staff", "admin"]}}),
+        );
+        let staff = new_user("a@example.com", "US", json!({"org": {"role": "staff"}}));
+
+        assert_eq!(
+            evaluate(&admin, "POST", "/admin/users"),
+            (PolicyAction::Allow, Some("admins".to_string()))
+        );
+        assert_eq!(
+            evaluate(&admin, "POST", "/administrator"),
+            (PolicyAction::Deny, None)
+        );
+        assert_eq!(
+            evaluate(&staff, "POST", "/admin"),
+            (PolicyAction::Deny, None)
+        );
+    }
+
+    #[test]
+    fn matches_methods_and_countries_case_insensitively() {
+        let reader = new_user("a@example.com", "NZ", json!({}));
+
+        assert_eq!(
+            evaluate(&reader, "get", "/"),
+            (PolicyAction::Allow, Some("readers".to_string()))
+        );
+        assert_eq!(evaluate(&reader, "POST", "/"), (PolicyAction::Deny, None));
+    }
+
+    #[test]
+    fn matches_services_by_common_name() {
+        assert_eq!(
+            evaluate(&new_service("ci.example.com"), "POST", "/deploy"),
+            (PolicyAction::Allow, Some("ci".to_string()))
+        );
+        assert_eq!(
+            evaluate(&new_service("other.example.com"), "POST", "/deploy"),
+            (PolicyAction::Deny, None)
+        );
+        assert_eq!(
+            evaluate(&new_service("ci.example.com"), "GET", "/"),
+            (PolicyAction::Deny, None)
+        );
+    }
+
+    #[test]
+    fn rejects_rules_that_can_never_match() {
+        for conditions in [
+            "{principal: service, emails: [a@example.com]}",
+            "{principal: service, claims: {role: [admin]}}",
+            "{principal: user, common_names: [ci.example.com]}",
+            "{countries: [au], common_names: [ci.example.com]}",
+        ] {
+            let yaml = format!("rules: [{{name: bad, action: allow, match: {conditions}}}]");
+            let policy: PolicySet = serde_yaml_ng::from_str(&yaml).unwrap();
+
+            assert!(policy.validate().is_err(), "{conditions}");
+        }
+
+        let policy: PolicySet = serde_yaml_ng::from_str(POLICY).unwrap();
+        assert!(policy.validate().is_ok());
+    }
+}
@@ -1,13 +1,391 @@
 use envoy_types::ext_authz::v3::pb::{Authorization, AuthorizationServer};
+use jnt::types::EmptyResult;
+use std::future::Future;
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tonic::transport::server::Router;
 use tonic::transport::Server;
+use tonic_health::pb::health_server::{Health, HealthServer};
+
+use crate::config::bootstrap::schema::KeySyncConfiguration;
+use crate::server::health::KeyHealthMonitor;
+use crate::server::metrics::Metrics;
+use crate::server::validator::TeamSetValidator;
 
 pub fn handle_error(error: Box<dyn std::error::Error>, message: &str, code: u8) -> ExitCode {
     log::error!("{}: {}", message, error);
     ExitCode::from(code)
 }
 
-pub fn new_router(server: impl Authorization) -> Router {
-    Server::builder().add_service(AuthorizationServer::new(server))
+pub fn new_router(server: Arc<impl Authorization>, health: HealthServer<impl Health>) -> Router {
+    Server::builder()
+        .add_service(health)
+        .add_service(AuthorizationServer::from_arc(server))
+}
+
+/// Resolves when the process receives SIGTERM or SIGINT
+pub async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log::error!("Failed to install SIGTERM handler: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+        _ = terminate => log::info!("Received SIGTERM"),
+    }
+}
+
+/// Runs the server until the shutdown signal, then reports NOT_SERVING and
+/// signals `drain` so the servers stop accepting connections. In-flight
+/// requests are given the shutdown timeout to complete before being abandoned.
+pub async fn serve_until_shutdown(
+    server: impl Future<Output = EmptyResult>,
+    forward_auth: Option<JoinHandle<()>>,
+    drain: watch::Sender<()>,
+    health: &KeyHealthMonitor,
+    shutdown_signal: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+) -> EmptyResult {
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result,
+        _ = shutdown_signal => {
+            log::info!("Shutting down, draining in-flight requests");
+            health.set_not_ready();
+            health.report().await;
+            drain.send(()).ok();
+
+            let drained = async {
+                let result = (&mut server).await;
+
+                if let Some(forward_auth) = forward_auth {
+                    forward_auth.await.ok();
+                }
+
+                result
+            };
+
+            match tokio::time::timeout(shutdown_timeout, drained).await {
+                Ok(result) => result,
+                Err(_) => {
+                    log::warn!("Shutdown timeout elapsed, abandoning in-flight requests");
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// The outcome of a key synchronisation across the requested teams
+pub struct SyncOutcome {
+    pub rotated: bool,
+    pub failed_team_names: Vec<String>,
+}
+
+/// Synchronises each team's keys, retrying the teams that failed with
+/// exponential backoff. Teams that keep failing are reported in the outcome.
+pub async fn sync_with_retry(
+    validator: Arc<TeamSetValidator>,
+    team_names: Vec<String>,
+    key_sync: &KeySyncConfiguration,
+    metrics: &Metrics,
+    health: &KeyHealthMonitor,
+) -> SyncOutcome {
+    let mut backoff = key_sync.retry_backoff;
+    let mut attempt = 1;
+    let mut rotated = false;
+    let mut pending = team_names;
+
+    loop {
+        let mut failed: Vec<(String, String)> = vec![];
+
+        for team_name in pending {
+            // The boxed error is not Send, so it must not be held across the backoff
+            let result = validator
+                .clone()
+                .sync_team_blocking(team_name.clone())
+                .await;
+            metrics.record_sync(&team_name, &result);
+            health.record_sync(&team_name, &result);
+
+            match result {
+                Ok(result) => rotated = result || rotated,
+                Err(e) => failed.push((team_name, e.to_string())),
+            }
+        }
+
+        if failed.is_empty() || attempt >= key_sync.retry_attempts {
+            for (team_name, error) in &failed {
+                log::error!(
+                    "Key syncronisation for team {team_name} failed after {attempt} attempts: {error}"
+                );
+            }
+
+            return SyncOutcome {
+                rotated,
+                failed_team_names: failed.into_iter().map(|(team_name, _)| team_name).collect(),
+            };
+        }
+
+        for (team_name, error) in &failed {
+            log::warn!(
+                "Key syncronisation attempt {attempt} of {} for team {team_name} failed, retrying in {}s: {error}",
+                key_sync.retry_attempts,
+                backoff.as_secs()
+            );
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(key_sync.retry_max_backoff);
+        attempt += 1;
+        pending = failed.into_iter().map(|(team_name, _)| team_name).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::health::AUTHORIZATION_SERVICE_NAME;
+    use crate::server::validator::new_fetching_validator;
+    use crate::socket::run_server;
+    use crate::testing::new_team_keys_json;
+    use envoy_types::ext_authz::v3::pb::{CheckRequest, CheckResponse};
+    use envoy_types::pb::envoy::service::auth::v3::authorization_client::AuthorizationClient;
+    use jnt::sockets::Listener;
+    use rust_cfzt_validator::api::TeamKeys;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use tokio::sync::{mpsc, oneshot, Notify};
+    use tokio::time::Instant;
+    use tonic::{Request, Response, Status};
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::server::{HealthReporter, HealthService};
+
+    /// Builds a validator for teams "flaky" and "steady" that records every
+    /// fetch. After the initial fetch, "flaky" fails `failures` times.
+    fn new_flaky_validator(
+        failures: usize,
+    ) -> (Arc<TeamSetValidator>, Arc<Mutex<HashMap<String, usize>>>) {
+        let fetches = Arc::new(Mutex::new(HashMap::new()));
+        let counted = fetches.clone();
+        let validator =
+            new_fetching_validator(&["flaky", "steady"], Duration::ZERO, move |team_name| {
+                let mut fetches = counted.lock().unwrap();
+                let fetch = fetches.entry(team_name.to_string()).or_insert(0);
+                *fetch += 1;
+
+                match team_name {
+                    "flaky" if (2..=failures + 1).contains(fetch) => Err("unavailable".into()),
+                    _ => TeamKeys::from_str(team_name, &new_team_keys_json(&["key-1"])),
+                }
+            });
+
+        (Arc::new(validator), fetches)
+    }
+
+    fn new_key_sync() -> KeySyncConfiguration {
+        KeySyncConfiguration {
+            retry_attempts: 4,
+            retry_backoff: Duration::from_secs(5),
+            retry_max_backoff: Duration::from_secs(12),
+            ..Default::default()
+        }
+    }
+
+    fn new_health() -> KeyHealthMonitor {
+        let (reporter, _) = tonic_health::server::health_reporter();
+        KeyHealthMonitor::new(reporter, Duration::from_secs(60))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_failed_teams_with_exponential_backoff() {
+        let (validator, fetches) = new_flaky_validator(3);
+        let team_names = vec!["flaky".to_string(), "steady".to_string()];
+        let started_at = Instant::now();
+
+        let outcome = sync_with_retry(
+            validator,
+            team_names,
+            &new_key_sync(),
+            &Metrics::new().unwrap(),
+            &new_health(),
+        )
+        .await;
+
+        assert!(!outcome.rotated);
+        assert!(outcome.failed_team_names.is_empty());
+        // Backs off 5s, then 10s, then is capped at 12s
+        assert_eq!(started_at.elapsed(), Duration::from_secs(27));
+
+        // Only the failing team is retried
+        let fetches = fetches.lock().unwrap();
+        assert_eq!(fetches["flaky"], 5);
+        assert_eq!(fetches["steady"], 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_teams_that_fail_every_attempt() {
+        let (validator, fetches) = new_flaky_validator(usize::MAX - 1);
+        let team_names = vec!["flaky".to_string(), "steady".to_string()];
+
+        let outcome = sync_with_retry(
+            validator,
+            team_names,
+            &new_key_sync(),
+            &Metrics::new().unwrap(),
+            &new_health(),
+        )
+        .await;
+
+        assert_eq!(outcome.failed_team_names, vec!["flaky".to_string()]);
+        assert_eq!(fetches.lock().unwrap()["flaky"], 5);
+    }
+
+    /// Holds every check open until released
+    struct PendingAuthorization {
+        started: mpsc::Sender<()>,
+        release: Arc<Notify>,
+    }
+
+    #[tonic::async_trait]
+    impl Authorization for PendingAuthorization {
+        async fn check(&self, _: Request<CheckRequest>) -> Result<Response<CheckResponse>, Status> {
+            self.started.send(()).await.ok();
+            self.release.notified().await;
+            Ok(Response::new(CheckResponse::default()))
+        }
+    }
+
+    struct ShutdownTest {
+        shutdown: oneshot::Sender<()>,
+        served: Pin<Box<dyn Future<Output = EmptyResult>>>,
+        check: JoinHandle<Result<Response<CheckResponse>, Status>>,
+        release: Arc<Notify>,
+        health: HealthService,
+    }
+
+    /// Serves a pending authorization, and starts a check that is held open
+    async fn start_shutdown_test(shutdown_timeout: Duration) -> ShutdownTest {
+        let (started_tx, mut started_rx) = mpsc::channel(1);
+        let release = Arc::new(Notify::new());
+        let authorization = PendingAuthorization {
+            started: started_tx,
+            release: release.clone(),
+        };
+
+        let reporter = HealthReporter::new();
+        let health = HealthService::from_health_reporter(reporter.clone());
+        let monitor = KeyHealthMonitor::new(reporter.clone(), Duration::from_secs(60));
+        monitor.set_ready();
+        monitor.report().await;
+
+        let router = new_router(
+            Arc::new(authorization),
+            HealthServer::new(HealthService::from_health_reporter(reporter)),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (drain_tx, mut drain_rx) = watch::channel(());
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let mut served = Box::pin(async move {
+            let drained = async move {
+                drain_rx.changed().await.ok();
+            };
+
+            serve_until_shutdown(
+                run_server(router, Listener::Tcp(listener), None, drained),
+                None,
+                drain_tx,
+                &monitor,
+                async {
+                    shutdown_rx.await.ok();
+                },
+                shutdown_timeout,
+            )
+            .await
+        });
+
+        let check = tokio::spawn(async move {
+            let mut client = AuthorizationClient::connect(url).await.unwrap();
+            client.check(CheckRequest::default()).await
+        });
+
+        tokio::select! {
+            _ = started_rx.recv() => {}
+            _ = &mut served => panic!("server stopped before the check started"),
+        }
+
+        ShutdownTest {
+            shutdown,
+            served,
+            check,
+            release,
+            health,
+        }
+    }
+
+    async fn get_serving_status(health: &HealthService) -> ServingStatus {
+        let request = Request::new(HealthCheckRequest {
+            service: AUTHORIZATION_SERVICE_NAME.to_string(),
+        });
+
+        health.check(request).await.unwrap().into_inner().status()
+    }
+
+    #[tokio::test]
+    async fn drains_in_flight_requests_after_reporting_not_serving() {
+        let test = start_shutdown_test(Duration::from_secs(30)).await;
+        assert_eq!(
+            get_serving_status(&test.health).await,
+            ServingStatus::Serving
+        );
+
+        test.shutdown.send(()).unwrap();
+        let mut served = test.served;
+
+        // The pending check holds the drain open
+        let draining = tokio::time::timeout(Duration::from_millis(100), &mut served).await;
+        assert!(draining.is_err());
+        assert_eq!(
+            get_serving_status(&test.health).await,
+            ServingStatus::NotServing
+        );
+
+        test.release.notify_one();
+        assert!(served.await.is_ok());
+        assert!(test.check.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn abandons_in_flight_requests_after_the_shutdown_timeout() {
+        let test = start_shutdown_test(Duration::from_millis(200)).await;
+        let started_at = Instant::now();
+
+        test.shutdown.send(()).unwrap();
+        assert!(test.served.await.is_ok());
+        assert!(started_at.elapsed() >= Duration::from_millis(200));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        test.check.abort();
+    }
 }
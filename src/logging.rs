@@ -0,0 +1,169 @@
+use std::io::Write;
+
+use env_logger::fmt::Formatter;
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+
+jnt::env!(discover_log_format_str, "LOG_FORMAT", "");
+
+struct JsonFieldVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+
+        // Fields that do not apply to an event, such as the subject of a denied request, are left empty
+        if !value.is_empty() {
+            self.0.insert(key.to_string(), JsonValue::String(value));
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the JSON object written for a record, holding its structured fields
+/// next to the standard ones
+fn to_json(timestamp: String, record: &Record) -> JsonValue {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), timestamp.into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+
+    record
+        .key_values()
+        .visit(&mut JsonFieldVisitor(&mut fields))
+        .ok();
+
+    JsonValue::Object(fields)
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let timestamp = buf.timestamp().to_string();
+    writeln!(buf, "{}", to_json(timestamp, record))
+}
+
+/// Initialises the logger from `RUST_LOG`. Setting `LOG_FORMAT=json` writes one
+/// JSON object per line, including any structured fields attached to the record.
+pub fn init_logger() {
+    let format = discover_log_format_str();
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if format.eq_ignore_ascii_case("json") {
+        builder.format(format_json);
+    }
+
+    builder.init();
+
+    if !format.is_empty() && !["json", "text"].contains(&format.to_lowercase().as_str()) {
+        log::warn!("Unknown LOG_FORMAT '{format}', using text");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::bootstrap::schema::TokenSource;
+    use crate::server::event::CheckEvent;
+    use crate::server::outcome::{CheckFailure, CheckOutcome};
+    use crate::server::request::{new_target, new_user_assertion, RequestTarget};
+    use log::{Level, Log, Metadata};
+    use std::sync::{Mutex, PoisonError};
+
+    const TIMESTAMP: &str = "2026-01-01T00:00:00Z";
+
+    /// Collects every record in the JSON log format, parsed back from its output
+    #[derive(Default)]
+    struct JsonLogger(Mutex<Vec<JsonValue>>);
+
+    impl Log for JsonLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let line = to_json(TIMESTAMP.to_string(), record).to_string();
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(serde_json::from_str(&line).unwrap());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn new_target_with_source() -> RequestTarget {
+        RequestTarget {
+            request_id: "request-1".to_string(),
+            source_address: "10.0.0.1".to_string(),
+            ..new_target("GET", "app.example.com", "/")
+        }
+    }
+
+    #[test]
+    fn formats_records_with_their_fields() {
+        let fields = [("request_id", "request-1"), ("subject", "")];
+        let record = Record::builder()
+            .args(format_args!("Request bypassed validation"))
+            .level(Level::Warn)
+            .target("extauthz")
+            .key_values(&fields)
+            .build();
+
+        let json: JsonValue =
+            serde_json::from_str(&to_json(TIMESTAMP.to_string(), &record).to_string()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": TIMESTAMP,
+                "level": "WARN",
+                "target": "extauthz",
+                "message": "Request bypassed validation",
+                "request_id": "request-1",
+            })
+        );
+    }
+
+    #[test]
+    fn logs_check_events_with_their_fields() {
+        let logger = JsonLogger::default();
+        let target = new_target_with_source();
+        let assertion = new_user_assertion();
+        let failure =
+            CheckFailure::permission_denied(CheckOutcome::PolicyDenied, "denied by policy")
+                .with_assertion(new_user_assertion());
+
+        CheckEvent::new(&target, Some(TokenSource::Header), Ok(&assertion)).log_to(&logger);
+        CheckEvent::new(&target, Some(TokenSource::Bearer), Err(&failure)).log_to(&logger);
+        CheckEvent::new_bypassed(&target).log_to(&logger);
+
+        let records = logger.0.into_inner().unwrap();
+        let allowed = &records[0];
+        assert_eq!(allowed["level"], "INFO");
+        assert_eq!(allowed["request_id"], "request-1");
+        assert_eq!(allowed["authority"], "app.example.com");
+        assert_eq!(allowed["path"], "/");
+        assert_eq!(allowed["method"], "GET");
+        assert_eq!(allowed["source_address"], "10.0.0.1");
+        assert_eq!(allowed["token_source"], "header");
+        assert_eq!(allowed["principal_type"], "user");
+        assert_eq!(allowed["subject"], "user-id");
+        assert_eq!(allowed["decision"], "allow");
+        assert_eq!(allowed["outcome"], "allowed");
+        assert!(allowed.get("reason").is_none());
+
+        let denied = &records[1];
+        assert_eq!(denied["subject"], "user-id");
+        assert_eq!(denied["decision"], "deny");
+        assert_eq!(denied["outcome"], "policy_denied");
+        assert_eq!(denied["reason"], "denied by policy");
+
+        let bypassed = &records[2];
+        assert_eq!(bypassed["message"], "Request bypassed validation");
+        assert_eq!(bypassed["principal_type"], "anonymous");
+        assert_eq!(bypassed["outcome"], "bypassed");
+        assert!(bypassed.get("token_source").is_none());
+        assert!(bypassed.get("subject").is_none());
+    }
+}
@@ -1,26 +1,36 @@
 mod config;
 mod helpers;
+mod logging;
 mod server;
 mod socket;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 use config::audience::discovery::discover_audience_provider;
 use config::audience::schema::AudienceProvider;
-use helpers::new_router;
+use helpers::{new_router, serve_until_shutdown, sync_with_retry, wait_for_shutdown_signal};
 use server::extauthz::CloudflareZeroTrustAuthorizationServer;
-use socket::run_server;
+use server::forward::new_forward_auth_router;
+use server::health::KeyHealthMonitor;
+use server::metrics::{new_metrics_router, Metrics};
+use socket::{run_http_server, run_server};
 use std::{process::ExitCode, sync::Arc};
 use tokio::runtime::Builder;
 use tokio_cron_scheduler::{Job, JobScheduler};
 
 use config::bootstrap::discovery::discover_bootstrap_configuration;
 use config::bootstrap::schema::Configuration as BootstrapConfiguration;
+use config::file::discovery::discover_configuration_file;
+use config::policy::discovery::discover_policy;
+use config::policy::schema::PolicySet;
 
 #[cfg(all(target_env = "musl", target_pointer_width = "64"))]
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc; // Use mimalloc allocator for Muslc targets
 
 fn main() -> ExitCode {
-    env_logger::init();
+    logging::init_logger();
     log::info!("Starting runtime");
 
     match start() {
@@ -35,55 +45,253 @@ fn start() -> Result<(), ExitCode> {
         .build()
         .map_err(|e| helpers::handle_error(Box::new(e), "error during runtime start", 1))?;
 
+    log::info!("Performing configuration file discovery");
+    let file_configuration = discover_configuration_file()
+        .map_err(|e| helpers::handle_error(e, "error during config file discovery", 2))?;
+
     log::info!("Performing bootstrap configuration discovery");
-    let configuration = discover_bootstrap_configuration()
+    let configuration = discover_bootstrap_configuration(file_configuration.bootstrap)
         .map_err(|e| helpers::handle_error(e, "error during config discovery", 2))?;
 
     log::info!("Performing audience provider discovery");
-    let aud_provider =
-        Arc::new(discover_audience_provider().map_err(|e| {
-            helpers::handle_error(e, "error during audience provider discovery", 3)
-        })?);
+    let aud_provider = Arc::new(
+        discover_audience_provider(file_configuration.audience)
+            .map_err(|e| helpers::handle_error(e, "error during audience provider discovery", 3))?,
+    );
+
+    log::info!("Performing policy discovery");
+    let policy = discover_policy(file_configuration.policy)
+        .map_err(|e| helpers::handle_error(e, "error during policy discovery", 4))?;
 
     runtime
-        .block_on(async_main(configuration, aud_provider.clone()))
+        .block_on(async_main(configuration, aud_provider.clone(), policy))
         .map_err(|e| helpers::handle_error(e, "error during execution", 100))
 }
 
 async fn async_main(
     bootstrap: BootstrapConfiguration,
     aud_provider: Arc<Box<dyn AudienceProvider>>,
+    policy: Option<PolicySet>,
 ) -> jnt::types::EmptyResult {
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    let health = Arc::new(KeyHealthMonitor::new(
+        reporter,
+        bootstrap.health.sync_failure_threshold,
+    ));
+    health.report().await;
+
     let listener = bootstrap.open_listener()?;
-    let validator = Arc::new(bootstrap.new_validator()?);
+    let tls = bootstrap.new_tls_config()?.map(Arc::new);
+    let metrics_listener = bootstrap.open_metrics_listener()?;
+    let forward_auth_listener = bootstrap.open_forward_auth_listener()?;
+    let metrics = Arc::new(Metrics::new()?);
+    let validator = Arc::new(
+        bootstrap
+            .new_validator()?
+            .with_health_monitor(health.clone()),
+    );
+    metrics.register_teams(&validator.get_refreshed_team_names());
+    health.register_teams(&validator.get_refreshed_team_names());
+    let cache = bootstrap.new_decision_cache().map(Arc::new);
+    let identity = bootstrap.new_identity_enricher()?.map(Arc::new);
+    let audit = bootstrap.new_audit_sink(metrics.clone())?.map(Arc::new);
+    let rate_limiter = bootstrap.new_rate_limiter().map(Arc::new);
+
+    if let Some(rate_limiter) = &rate_limiter {
+        metrics.set_rate_limiter(rate_limiter.clone());
+    }
     let mut scheduler = JobScheduler::new().await?;
 
-    let router = new_router(CloudflareZeroTrustAuthorizationServer::new(
-        validator.clone(),
-        aud_provider,
-        &bootstrap.validator.get_default_team_name(),
-        bootstrap.nbf_validation,
-        bootstrap.exp_validation,
-    ));
+    health.set_ready();
+    health.report().await;
 
-    if bootstrap.validator.requires_refresh() {
+    let requires_refresh = bootstrap.get_validator()?.requires_refresh();
+    let server = Arc::new(
+        CloudflareZeroTrustAuthorizationServer::new(
+            validator.clone(),
+            aud_provider.clone(),
+            metrics.clone(),
+            &bootstrap.get_validator()?.get_team_names(),
+            bootstrap.nbf_validation,
+            bootstrap.exp_validation,
+        )
+        .with_time_constraints(bootstrap.leeway, bootstrap.max_token_age)
+        .with_policy(policy)
+        .with_cache(cache.clone())
+        .with_identity_enricher(identity)
+        .with_audit_sink(audit.clone())
+        .with_bypass_rules(bootstrap.bypass)
+        .with_rate_limiter(rate_limiter.clone())
+        .with_service_allowlist(bootstrap.service_allowlist)
+        .with_token_sources(bootstrap.token_sources)
+        .with_remove_token_header(bootstrap.remove_token_header)
+        .with_headers(bootstrap.headers)
+        .with_denied_response(bootstrap.denied_response),
+    );
+    let router = new_router(server.clone(), health_service);
+
+    if requires_refresh {
         log::info!("Registering validator syncronisation job");
+        let sync_health = health.clone();
+        let sync_metrics = metrics.clone();
+        let sync_validator = validator.clone();
+        let sync_cache = cache.clone();
+        let key_sync = Arc::new(bootstrap.key_sync);
+        let sync_running = Arc::new(tokio::sync::Mutex::new(()));
+        scheduler
+            .add(Job::new_async(bootstrap.sync_schedule, move |_, _| {
+                let health = sync_health.clone();
+                let metrics = sync_metrics.clone();
+                let validator = sync_validator.clone();
+                let cache = sync_cache.clone();
+                let key_sync = key_sync.clone();
+                let sync_running = sync_running.clone();
+
+                Box::pin(async move {
+                    // A synchronisation still retrying failed teams covers this run
+                    let Ok(_running) = sync_running.try_lock() else {
+                        log::info!("Validator syncronisation still retrying, skipping");
+                        return;
+                    };
+
+                    log::info!("Triggering validator syncronisation");
+                    let mut team_names = validator.get_refreshed_team_names();
+
+                    loop {
+                        let outcome = sync_with_retry(
+                            validator.clone(),
+                            team_names,
+                            &key_sync,
+                            &metrics,
+                            &health,
+                        )
+                        .await;
+
+                        // Keys that rotated are in use even when another team failed
+                        if let (true, Some(cache)) = (outcome.rotated, &cache) {
+                            log::info!("Keys rotated, clearing decision cache");
+                            cache.clear();
+                        }
+
+                        if outcome.failed_team_names.is_empty() {
+                            break;
+                        }
+
+                        // Keep retrying rather than wait for the next scheduled run
+                        tokio::time::sleep(key_sync.retry_max_backoff).await;
+                        team_names = outcome.failed_team_names;
+                    }
+                })
+            })?)
+            .await?;
+    }
+
+    log::info!("Registering health reporting job");
+    let report_health = health.clone();
+    scheduler
+        .add(Job::new_async(
+            bootstrap.health.check_schedule.as_str(),
+            move |_, _| {
+                let health = report_health.clone();
+                Box::pin(async move { health.report().await })
+            },
+        )?)
+        .await?;
+
+    if let Some(rate_limiter) = rate_limiter {
+        log::info!("Registering rate limiter sweep job");
         scheduler
-            .add(Job::new(bootstrap.sync_schedule, move |_, _| {
-                log::info!("Triggering validator syncronisation");
-                let _ = validator.sync();
+            .add(Job::new(
+                bootstrap.rate_limit.sweep_schedule.as_str(),
+                move |_, _| {
+                    let removed = rate_limiter.sweep();
+                    log::debug!("Rate limiter sweep removed {removed} refilled sources");
+                },
+            )?)
+            .await?;
+    }
+
+    if let Some(schedule) = aud_provider.get_refresh_schedule() {
+        log::info!("Registering audience provider refresh job");
+        scheduler
+            .add(Job::new(schedule.as_str(), move |_, _| {
+                match aud_provider.refresh() {
+                    Ok(true) => log::info!("Audience provider refreshed with new audiences"),
+                    Ok(false) => log::debug!("Audience provider unchanged"),
+                    Err(e) => log::error!("Audience provider refresh failed: {e}"),
+                }
             })?)
             .await?;
     }
 
+    if let Some(tls) = tls.clone() {
+        log::info!("Registering TLS certificate reload job");
+        scheduler
+            .add(Job::new(
+                tls.get_reload_schedule().to_string().as_str(),
+                move |_, _| match tls.refresh() {
+                    Ok(true) => log::info!("TLS certificates reloaded"),
+                    Ok(false) => log::debug!("TLS certificates unchanged"),
+                    Err(e) => log::error!("TLS certificate reload failed: {e}"),
+                },
+            )?)
+            .await?;
+    }
+
     log::info!("Starting validation syncronisation job");
     scheduler.start().await?;
 
+    if let Some(metrics_listener) = metrics_listener {
+        log::info!("Running metrics server");
+        tokio::spawn(async move {
+            let router = new_metrics_router(metrics);
+
+            if let Err(e) = run_http_server(router, metrics_listener, std::future::pending()).await
+            {
+                log::error!("Metrics server failed: {e}");
+            }
+        });
+    }
+
+    let (drain_tx, drain_rx) = tokio::sync::watch::channel(());
+    let drained = move || {
+        let mut drain_rx = drain_rx.clone();
+        async move {
+            drain_rx.changed().await.ok();
+        }
+    };
+
+    let forward_auth = forward_auth_listener.map(|forward_auth_listener| {
+        log::info!("Running forward auth server");
+        log::info!("Forward auth cannot remove client headers, the proxy must copy every identity header");
+        let router = new_forward_auth_router(server, bootstrap.forward_auth_headers);
+        let signal = drained();
+
+        tokio::spawn(async move {
+            if let Err(e) = run_http_server(router, forward_auth_listener, signal).await {
+                log::error!("Forward auth server failed: {e}");
+            }
+        })
+    });
+
     log::info!("Running ExtAuthz server");
-    run_server(router, listener).await?;
+    serve_until_shutdown(
+        run_server(router, listener, tls.clone(), drained()),
+        forward_auth,
+        drain_tx,
+        &health,
+        wait_for_shutdown_signal(),
+        bootstrap.shutdown_timeout,
+    )
+    .await?;
 
     log::info!("Server stopped, shutting down validation syncronisation job");
     scheduler.shutdown().await?;
 
+    if let Some(audit) = audit {
+        log::info!("Flushing audit log");
+        audit.close();
+    }
+
     Ok(())
 }
@@ -0,0 +1,361 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use jnt::types::StdResult;
+use serde::Serialize;
+
+use super::event::CheckEvent;
+use super::metrics::Metrics;
+use super::request::PrincipalAssertion;
+use crate::config::bootstrap::schema::AuditConfiguration;
+
+#[derive(Serialize)]
+struct AuditPrincipal<'a> {
+    #[serde(rename = "type")]
+    typ: &'static str,
+    subject: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    common_name: Option<&'a str>,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Serialize)]
+struct AuditTarget<'a> {
+    authority: &'a str,
+    path: &'a str,
+    method: &'a str,
+    source_address: &'a str,
+}
+
+/// One JSON Lines record of an authorization decision.
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    timestamp: String,
+    request_id: &'a str,
+    decision: &'static str,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_source: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    principal: Option<AuditPrincipal<'a>>,
+    audiences: Vec<&'a str>,
+    target: AuditTarget<'a>,
+}
+
+impl<'a> AuditRecord<'a> {
+    /// Builds the record for a decision. The audiences are those of the token
+    /// that matched the request's route.
+    pub fn new(
+        event: &CheckEvent<'a>,
+        assertion: Option<&'a PrincipalAssertion>,
+        route_audiences: &[String],
+    ) -> Self {
+        let principal = assertion.map(|assertion| match assertion {
+            PrincipalAssertion::User(user) => AuditPrincipal {
+                typ: "user",
+                subject: &user.sub,
+                email: Some(&user.email),
+                common_name: None,
+                iat: user.iat,
+                exp: user.exp,
+            },
+            PrincipalAssertion::Service(service) => AuditPrincipal {
+                typ: "service",
+                subject: &service.common_name,
+                email: None,
+                common_name: Some(&service.common_name),
+                iat: service.iat,
+                exp: service.exp,
+            },
+        });
+
+        let audiences = assertion
+            .map(|assertion| {
+                assertion
+                    .get_audiences()
+                    .iter()
+                    .filter(|audience| route_audiences.contains(audience))
+                    .map(|audience| audience.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        AuditRecord {
+            timestamp: jiff::Timestamp::now().to_string(),
+            request_id: event.request_id,
+            decision: event.decision,
+            outcome: event.outcome,
+            reason: event.reason,
+            token_source: event.token_source,
+            principal,
+            audiences,
+            target: AuditTarget {
+                authority: event.authority,
+                path: event.path,
+                method: event.method,
+                source_address: event.source_address,
+            },
+        }
+    }
+}
+
+/// Appends to a file, rotating it to `<path>.1`, `<path>.2`... once it would
+/// grow past the maximum size. The oldest file beyond `max_files` is removed.
+struct RotatingFile {
+    path: String,
+    max_file_size: u64,
+    max_files: usize,
+    size: u64,
+    writer: BufWriter<File>,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_file_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_file_size,
+            max_files,
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).ok();
+        } else {
+            fs::remove_file(format!("{}.{}", self.path, self.max_files)).ok();
+
+            for index in (1..self.max_files).rev() {
+                fs::rename(
+                    format!("{}.{index}", self.path),
+                    format!("{}.{}", self.path, index + 1),
+                )
+                .ok();
+            }
+
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        let reopened = RotatingFile::open(&self.path, self.max_file_size, self.max_files)?;
+        *self = reopened;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_file_size {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_records(
+    receiver: Receiver<String>,
+    mut output: Box<dyn Write + Send>,
+    metrics: Arc<Metrics>,
+) {
+    while let Ok(line) = receiver.recv() {
+        let mut batch = 1;
+        let mut result = output.write_all(line.as_bytes());
+
+        // Drain whatever else is queued before flushing, leaving it queued after an error
+        while result.is_ok() {
+            let Ok(line) = receiver.try_recv() else {
+                break;
+            };
+
+            batch += 1;
+            result = output.write_all(line.as_bytes());
+        }
+
+        // A failed write loses its own record, a failed flush the buffered batch
+        let (result, dropped) = match result {
+            Ok(()) => (output.flush(), batch),
+            Err(e) => (Err(e), 1),
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to write audit records: {e}");
+            metrics.record_audit_dropped(dropped);
+        }
+    }
+}
+
+/// Writes audit records as JSON Lines on a dedicated thread. Records are
+/// queued in a bounded buffer, and dropped rather than blocking when it is full.
+pub struct AuditSink {
+    sender: Mutex<Option<SyncSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AuditSink {
+    pub fn new(configuration: &AuditConfiguration, metrics: Arc<Metrics>) -> StdResult<Self> {
+        let output: Box<dyn Write + Send> = match &configuration.file {
+            Some(path) => Box::new(RotatingFile::open(
+                path,
+                configuration.max_file_size,
+                configuration.max_files,
+            )?),
+            None => Box::new(io::stdout()),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(configuration.buffer_size);
+        let writer = thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_records(receiver, output, metrics))?;
+
+        Ok(AuditSink {
+            sender: Mutex::new(Some(sender)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Queues a record for writing.
+    /// Returns false if the record was dropped.
+    pub fn record(&self, record: &AuditRecord) -> bool {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to serialise audit record: {e}");
+                return false;
+            }
+        };
+        line.push('\n');
+
+        let sender = self.sender.lock().unwrap_or_else(PoisonError::into_inner);
+
+        sender
+            .as_ref()
+            .is_some_and(|sender| sender.try_send(line).is_ok())
+    }
+
+    /// Stops accepting records and waits for queued records to be written.
+    pub fn close(&self) {
+        self.sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        if let Some(writer) = writer {
+            writer.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::bootstrap::schema::TokenSource;
+    use crate::server::outcome::{CheckFailure, CheckOutcome};
+    use crate::server::request::{new_target, new_user_assertion};
+    use crate::testing::new_temp_dir;
+
+    fn read(path: &str) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotates_files_past_the_size_limit() {
+        let dir = new_temp_dir("audit-rotation");
+        let path = dir.join("audit.log").to_string_lossy().to_string();
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(read(&path), "fourth\n");
+        assert_eq!(read(&format!("{path}.1")), "third\n");
+        assert_eq!(read(&format!("{path}.2")), "second\n");
+        assert!(fs::metadata(format!("{path}.3")).is_err());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn appends_until_the_size_limit() {
+        let dir = new_temp_dir("audit-append");
+        let path = dir.join("audit.log").to_string_lossy().to_string();
+        fs::write(&path, "old\n").unwrap();
+
+        let mut file = RotatingFile::open(&path, 10, 1).unwrap();
+        file.write_all(b"new\n").unwrap();
+        file.write_all(b"next\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(read(&path), "next\n");
+        assert_eq!(read(&format!("{path}.1")), "old\nnew\n");
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn truncates_without_keeping_rotated_files() {
+        let dir = new_temp_dir("audit-truncate");
+        let path = dir.join("audit.log").to_string_lossy().to_string();
+
+        let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(read(&path), "second\n");
+        assert!(fs::metadata(format!("{path}.1")).is_err());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn records_the_principal_of_denied_tokens() {
+        let target = new_target("POST", "app.example.com", "/admin");
+        let failure =
+            CheckFailure::permission_denied(CheckOutcome::PolicyDenied, "denied by default policy")
+                .with_assertion(new_user_assertion());
+        let event = CheckEvent::new(&target, Some(TokenSource::Header), Err(&failure));
+        assert_eq!(event.principal_type, Some("user"));
+        assert_eq!(event.subject, Some("user-id"));
+
+        let route_audiences = vec!["app-aud".to_string(), "other-aud".to_string()];
+
+        let record = AuditRecord::new(&event, failure.assertion.as_deref(), &route_audiences);
+        let record = serde_json::to_value(&record).unwrap();
+
+        assert_eq!(record["decision"], "deny");
+        assert_eq!(record["outcome"], "policy_denied");
+        assert_eq!(record["principal"]["type"], "user");
+        assert_eq!(record["principal"]["subject"], "user-id");
+        assert_eq!(record["principal"]["email"], "user@example.com");
+        assert_eq!(record["principal"]["iat"], 1700000000);
+        assert_eq!(record["principal"]["exp"], 1700003600);
+        assert_eq!(record["audiences"], serde_json::json!(["app-aud"]));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::digest::{digest, SHA256};
+
+use super::request::PrincipalAssertion;
+
+type TokenHash = [u8; 32];
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+struct CacheEntry<V> {
+    value: V,
+    expires_at: u64,
+    sequence: u64,
+}
+
+/// The cached values, along with their keys ordered by expiry so that the
+/// entry closest to expiry is found without scanning every value
+struct CacheEntries<K, V> {
+    values: HashMap<K, CacheEntry<V>>,
+    expiries: BTreeMap<(u64, u64), K>,
+    next_sequence: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> CacheEntries<K, V> {
+    fn new() -> Self {
+        CacheEntries {
+            values: HashMap::new(),
+            expiries: BTreeMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn insert(&mut self, key: K, value: V, expires_at: u64) {
+        self.remove(&key);
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.expiries.insert((expires_at, sequence), key.clone());
+        self.values.insert(
+            key,
+            CacheEntry {
+                value,
+                expires_at,
+                sequence,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.values.remove(key) {
+            self.expiries.remove(&(entry.expires_at, entry.sequence));
+        }
+    }
+
+    /// Removes the entry closest to expiry if it expires by `expires_by`,
+    /// returning whether one was removed
+    fn remove_first(&mut self, expires_by: u64) -> bool {
+        match self.expiries.first_key_value() {
+            Some((&(expires_at, _), _)) if expires_at <= expires_by => {
+                if let Some((_, key)) = self.expiries.pop_first() {
+                    self.values.remove(&key);
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.expiries.clear();
+    }
+}
+
+/// A bounded map whose entries expire at a fixed unix time. When full,
+/// expired entries are purged first, then the entry closest to expiry is evicted.
+pub struct ExpiringCache<K, V> {
+    max_entries: usize,
+    entries: Mutex<CacheEntries<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ExpiringCache<K, V> {
+    pub fn new(max_entries: usize) -> Self {
+        ExpiringCache {
+            max_entries,
+            entries: Mutex::new(CacheEntries::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheEntries<K, V>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.lock();
+
+        match entries.values.get(key) {
+            Some(entry) if entry.expires_at > now_secs() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V, expires_at: u64) {
+        let now = now_secs();
+
+        if self.max_entries == 0 || expires_at <= now {
+            return;
+        }
+
+        let mut entries = self.lock();
+        entries.remove(&key);
+
+        if entries.len() >= self.max_entries {
+            while entries.remove_first(now) {}
+        }
+
+        if entries.len() >= self.max_entries {
+            entries.remove_first(u64::MAX);
+        }
+
+        entries.insert(key, value, expires_at);
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+/// A bounded cache of validated tokens, keyed by the SHA-256 of the raw token.
+/// Entries live until the token's `exp` or the configured maximum TTL,
+/// whichever comes first.
+pub struct DecisionCache {
+    max_ttl: Duration,
+    entries: ExpiringCache<TokenHash, PrincipalAssertion>,
+}
+
+fn hash_token(token: &str) -> TokenHash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(digest(&SHA256, token.as_bytes()).as_ref());
+    hash
+}
+
+impl DecisionCache {
+    pub fn new(max_entries: usize, max_ttl: Duration) -> Self {
+        DecisionCache {
+            max_ttl,
+            entries: ExpiringCache::new(max_entries),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.entries.max_entries > 0 && !self.max_ttl.is_zero()
+    }
+
+    pub fn get(&self, token: &str) -> Option<PrincipalAssertion> {
+        self.entries.get(&hash_token(token))
+    }
+
+    pub fn insert(&self, token: &str, assertion: &PrincipalAssertion) {
+        let expires_at = assertion
+            .get_expiry()
+            .min(now_secs().saturating_add(self.max_ttl.as_secs()));
+
+        self.entries
+            .insert(hash_token(token), assertion.clone(), expires_at);
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_user_assertion;
+
+    fn insert_expired(cache: &ExpiringCache<&'static str, u32>, key: &'static str, value: u32) {
+        cache.lock().insert(key, value, now_secs());
+    }
+
+    #[test]
+    fn returns_entries_until_expiry() {
+        let cache = ExpiringCache::new(10);
+        let now = now_secs();
+
+        cache.insert("a", 1, now + 60);
+        cache.insert("b", 2, now);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+
+        insert_expired(&cache, "c", 3);
+        assert_eq!(cache.get(&"c"), None);
+        assert_eq!(cache.lock().len(), 1);
+
+        cache.clear();
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn evicts_expired_entries_first() {
+        let cache = ExpiringCache::new(3);
+        let now = now_secs();
+
+        cache.insert("a", 1, now + 60);
+        insert_expired(&cache, "b", 2);
+        insert_expired(&cache, "c", 3);
+        cache.insert("d", 4, now + 120);
+
+        assert_eq!(cache.lock().len(), 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"d"), Some(4));
+    }
+
+    #[test]
+    fn evicts_entry_closest_to_expiry() {
+        let cache = ExpiringCache::new(2);
+        let now = now_secs();
+
+        cache.insert("a", 1, now + 60);
+        cache.insert("b", 2, now + 30);
+        cache.insert("c", 3, now + 120);
+
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(3));
+    }
+
+    #[test]
+    fn stores_nothing_without_capacity() {
+        let cache = ExpiringCache::new(0);
+
+        cache.insert("a", 1, now_secs() + 60);
+        assert_eq!(cache.get(&"a"), None);
+    }
+
+    #[test]
+    fn keeps_the_expiry_order_when_entries_are_replaced() {
+        let cache = ExpiringCache::new(2);
+        let now = now_secs();
+
+        cache.insert("a", 1, now + 30);
+        cache.insert("b", 2, now + 60);
+        cache.insert("a", 3, now + 120);
+        cache.insert("c", 4, now + 90);
+
+        assert_eq!(cache.get(&"a"), Some(3));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(4));
+        assert_eq!(cache.lock().expiries.len(), 2);
+    }
+
+    fn get_expiry(cache: &DecisionCache, token: &str) -> u64 {
+        cache.entries.lock().values[&hash_token(token)].expires_at
+    }
+
+    fn new_assertion(exp: u64) -> PrincipalAssertion {
+        let mut assertion = new_user_assertion();
+
+        if let PrincipalAssertion::User(user) = &mut assertion {
+            user.exp = exp;
+        }
+
+        assertion
+    }
+
+    #[test]
+    fn caps_decisions_at_the_token_expiry_and_max_ttl() {
+        let cache = DecisionCache::new(10, Duration::from_secs(60));
+        let now = now_secs();
+
+        cache.insert("long-lived", &new_assertion(now + 3600));
+        cache.insert("short-lived", &new_assertion(now + 30));
+        cache.insert("expired", &new_assertion(now.saturating_sub(1)));
+
+        assert!((now + 60..=now + 61).contains(&get_expiry(&cache, "long-lived")));
+        assert_eq!(get_expiry(&cache, "short-lived"), now + 30);
+        assert!(cache.get("expired").is_none());
+        assert!(cache.get("short-lived").is_some());
+    }
+
+    #[test]
+    fn clears_every_decision() {
+        let cache = DecisionCache::new(10, Duration::from_secs(60));
+        let exp = now_secs() + 3600;
+
+        cache.insert("first", &new_assertion(exp));
+        cache.insert("second", &new_assertion(exp));
+        cache.clear();
+
+        assert!(cache.get("first").is_none());
+        assert!(cache.get("second").is_none());
+    }
+}
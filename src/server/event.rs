@@ -0,0 +1,139 @@
+use log::{Level, Log, Metadata, Record};
+
+use super::outcome::{CheckFailure, CheckOutcome};
+use super::request::{PrincipalAssertion, RequestTarget};
+use crate::config::bootstrap::schema::TokenSource;
+
+/// A summary of one authorization decision and the request it was made for.
+pub struct CheckEvent<'a> {
+    pub request_id: &'a str,
+    pub authority: &'a str,
+    pub path: &'a str,
+    pub method: &'a str,
+    pub source_address: &'a str,
+    pub token_source: Option<&'static str>,
+    pub principal_type: Option<&'static str>,
+    pub subject: Option<&'a str>,
+    pub decision: &'static str,
+    pub outcome: &'static str,
+    pub reason: Option<&'a str>,
+}
+
+impl<'a> CheckEvent<'a> {
+    pub fn new(
+        target: &'a RequestTarget,
+        source: Option<TokenSource>,
+        result: Result<&'a PrincipalAssertion, &'a CheckFailure>,
+    ) -> Self {
+        let mut event = CheckEvent {
+            request_id: &target.request_id,
+            authority: &target.authority,
+            path: &target.path,
+            method: &target.method,
+            source_address: &target.source_address,
+            token_source: source.map(|source| source.as_str()),
+            principal_type: None,
+            subject: None,
+            decision: "allow",
+            outcome: CheckOutcome::Allowed.as_str(),
+            reason: None,
+        };
+
+        let assertion = match result {
+            Ok(assertion) => Some(assertion),
+            Err(failure) => {
+                event.decision = "deny";
+                event.outcome = failure.outcome.as_str();
+                event.reason = Some(failure.status.message());
+                failure.assertion.as_deref()
+            }
+        };
+
+        match assertion {
+            Some(PrincipalAssertion::User(user)) => {
+                event.principal_type = Some("user");
+                event.subject = Some(&user.sub);
+            }
+            Some(PrincipalAssertion::Service(service)) => {
+                event.principal_type = Some("service");
+                event.subject = Some(&service.common_name);
+            }
+            None => {}
+        }
+
+        event
+    }
+
+    /// Builds the event for a request allowed by a bypass rule.
+    pub fn new_bypassed(target: &'a RequestTarget) -> Self {
+        CheckEvent {
+            request_id: &target.request_id,
+            authority: &target.authority,
+            path: &target.path,
+            method: &target.method,
+            source_address: &target.source_address,
+            token_source: None,
+            principal_type: Some("anonymous"),
+            subject: None,
+            decision: "allow",
+            outcome: CheckOutcome::Bypassed.as_str(),
+            reason: None,
+        }
+    }
+
+    /// Logs the event as a single record, with every attribute attached as a
+    /// structured field for the JSON log format.
+    pub fn log(&self) {
+        self.log_to(log::logger());
+    }
+
+    /// Writes the event's record to a logger, if the logger enables its level.
+    pub fn log_to(&self, logger: &dyn Log) {
+        let (level, message) = match (self.token_source, self.reason) {
+            (None, None) => (Level::Info, "Request bypassed validation".to_string()),
+            (Some(source), None) => (
+                Level::Info,
+                format!("Request passed validation (source: {source})"),
+            ),
+            (Some(source), Some(reason)) => (
+                Level::Info,
+                format!("Request failed validation (source: {source}): {reason}"),
+            ),
+            (None, _) => (Level::Warn, "Request missing JWT".to_string()),
+        };
+
+        let metadata = Metadata::builder()
+            .level(level)
+            .target(module_path!())
+            .build();
+
+        if !logger.enabled(&metadata) {
+            return;
+        }
+
+        let fields = [
+            ("request_id", self.request_id),
+            ("authority", self.authority),
+            ("path", self.path),
+            ("method", self.method),
+            ("source_address", self.source_address),
+            ("token_source", self.token_source.unwrap_or_default()),
+            ("principal_type", self.principal_type.unwrap_or_default()),
+            ("subject", self.subject.unwrap_or_default()),
+            ("decision", self.decision),
+            ("outcome", self.outcome),
+            ("reason", self.reason.unwrap_or_default()),
+        ];
+
+        logger.log(
+            &Record::builder()
+                .metadata(metadata)
+                .args(format_args!("{message}"))
+                .module_path_static(Some(module_path!()))
+                .file_static(Some(file!()))
+                .line(Some(line!()))
+                .key_values(&fields)
+                .build(),
+        );
+    }
+}
@@ -1,70 +1,501 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use envoy_types::ext_authz::v3::{
     pb::{Authorization, CheckRequest, CheckResponse},
     CheckResponseExt, OkHttpResponseBuilder,
 };
 use jsonwebtoken::{Algorithm, Validation};
-use rust_cfzt_validator::Validator;
 use tonic::{Request, Response, Status};
 
 use super::{
-    request::{get_headers, PrincipalAssertion},
-    response::ResponseMutator,
+    audit::{AuditRecord, AuditSink},
+    cache::{now_secs, DecisionCache},
+    event::CheckEvent,
+    identity::IdentityEnricher,
+    metrics::Metrics,
+    outcome::{CheckFailure, CheckOutcome},
+    ratelimit::RateLimiter,
+    request::{get_headers, get_token, PrincipalAssertion, RequestTarget},
+    response::{
+        new_anonymous_dynamic_metadata, new_denied_response, new_dynamic_metadata,
+        remove_spoofed_headers, set_header, ResponseMutator,
+    },
+    validator::{get_team_issuer, get_unverified_issuer, KeyValidationError, TeamSetValidator},
 };
 use crate::config::audience::schema::AudienceProvider;
-use crate::config::bootstrap::schema::TimeConstraintMode;
+use crate::config::bootstrap::schema::{
+    BypassRule, DeniedResponseConfiguration, HeaderConfiguration, ServiceGrant, TimeConstraintMode,
+    TokenSource,
+};
+use crate::config::policy::schema::{PolicyAction, PolicySet};
+
+/// The validation library's default leeway, which `Strict` has always used
+const STRICT_LEEWAY: u64 = 60;
+
+/// Applies the nbf and exp modes to the library's validation. The library has a
+/// single leeway for both claims, so its time checks are turned off and each
+/// checked claim is returned with its own leeway for `check_time_claims`.
+fn apply_time_constraints(
+    constraints: &mut Validation,
+    nbf_validation: &TimeConstraintMode,
+    exp_validation: &TimeConstraintMode,
+    leeway: Duration,
+) -> Vec<(&'static str, u64)> {
+    constraints.validate_nbf = false;
+    constraints.validate_exp = false;
+
+    [("nbf", nbf_validation), ("exp", exp_validation)]
+        .into_iter()
+        .filter_map(|(claim, mode)| {
+            let claim_leeway = match mode {
+                // The library never checked nbf unless asked to
+                TimeConstraintMode::Strict if claim == "nbf" => return None,
+                TimeConstraintMode::Strict => STRICT_LEEWAY,
+                TimeConstraintMode::Exact => 0,
+                TimeConstraintMode::Leeway => leeway.as_secs(),
+                TimeConstraintMode::Lax => return None,
+            };
+
+            Some((claim, claim_leeway))
+        })
+        .collect()
+}
+
+/// Checks the time claims left out of the library's validation, each with its leeway
+fn check_time_claims(
+    claims: &serde_json::Value,
+    time_claims: &[(&str, u64)],
+    now: u64,
+) -> Result<(), &'static str> {
+    for (claim, leeway) in time_claims {
+        match (*claim, claims.get(claim).and_then(|value| value.as_u64())) {
+            ("nbf", Some(nbf)) if nbf > now.saturating_add(*leeway) => {
+                return Err("ImmatureSignature")
+            }
+            ("exp", Some(exp)) if exp < now.saturating_sub(*leeway) => {
+                return Err("ExpiredSignature")
+            }
+            ("exp", None) => return Err("Missing required claim: exp"),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Selects the team whose keys validate a token, by the token's unverified
+/// issuer. Returns the issuer and the team name.
+fn resolve_issuer_team<'a>(
+    issuers: &'a HashMap<String, String>,
+    token: &str,
+) -> super::CheckResult<(&'a String, &'a String)> {
+    let issuer = get_unverified_issuer(token).map_err(|e| {
+        CheckFailure::unauthenticated(
+            CheckOutcome::JwtFailure,
+            format!("failed CF JWT decoding: {e}"),
+        )
+    })?;
+
+    issuers.get_key_value(&issuer).ok_or_else(|| {
+        CheckFailure::unauthenticated(
+            CheckOutcome::JwtFailure,
+            format!("unknown CF JWT issuer: {issuer}"),
+        )
+    })
+}
 
 pub struct CloudflareZeroTrustAuthorizationServer {
-    validator: Arc<Box<dyn Validator>>,
+    validator: Arc<TeamSetValidator>,
     aud_provider: Arc<Box<dyn AudienceProvider>>,
-    default_team_name: String,
+    metrics: Arc<Metrics>,
+    issuers: HashMap<String, String>,
     nbf_validation: TimeConstraintMode,
     exp_validation: TimeConstraintMode,
+    leeway: Duration,
+    max_token_age: Option<Duration>,
+    policy: Option<PolicySet>,
+    denied_response: DeniedResponseConfiguration,
+    cache: Option<Arc<DecisionCache>>,
+    identity: Option<Arc<IdentityEnricher>>,
+    token_sources: Vec<TokenSource>,
+    remove_token_header: bool,
+    headers: HeaderConfiguration,
+    audit: Option<Arc<AuditSink>>,
+    bypass: Vec<BypassRule>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    service_allowlist: HashMap<String, Vec<ServiceGrant>>,
 }
 
 impl CloudflareZeroTrustAuthorizationServer {
     pub fn new(
-        validator: Arc<Box<dyn Validator>>,
+        validator: Arc<TeamSetValidator>,
         aud_provider: Arc<Box<dyn AudienceProvider>>,
-        default_team_name: &str,
+        metrics: Arc<Metrics>,
+        team_names: &[String],
         nbf_validation: TimeConstraintMode,
         exp_validation: TimeConstraintMode,
     ) -> Self {
         CloudflareZeroTrustAuthorizationServer {
             validator,
             aud_provider,
-            default_team_name: default_team_name.to_string(),
+            metrics,
+            issuers: team_names
+                .iter()
+                .map(|team_name| (get_team_issuer(team_name), team_name.to_string()))
+                .collect(),
             nbf_validation,
             exp_validation,
+            leeway: Duration::from_secs(60),
+            max_token_age: None,
+            policy: None,
+            denied_response: DeniedResponseConfiguration::default(),
+            cache: None,
+            identity: None,
+            token_sources: vec![TokenSource::Header],
+            remove_token_header: false,
+            headers: HeaderConfiguration::default(),
+            audit: None,
+            bypass: vec![],
+            rate_limiter: None,
+            service_allowlist: HashMap::new(),
         }
     }
 
-    fn validate(&self, token: &str) -> super::StatusResult<PrincipalAssertion> {
-        let mut constraints = Validation::new(Algorithm::RS256);
-        constraints.set_audience(&self.aud_provider.get_audiences());
+    pub fn with_service_allowlist(
+        mut self,
+        service_allowlist: HashMap<String, Vec<ServiceGrant>>,
+    ) -> Self {
+        self.service_allowlist = service_allowlist;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn with_bypass_rules(mut self, bypass: Vec<BypassRule>) -> Self {
+        self.bypass = bypass;
+        self
+    }
 
-        if self.nbf_validation == TimeConstraintMode::Lax {
-            constraints.validate_nbf = false;
+    pub fn with_audit_sink(mut self, audit: Option<Arc<AuditSink>>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn with_headers(mut self, headers: HeaderConfiguration) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn with_remove_token_header(mut self, remove_token_header: bool) -> Self {
+        self.remove_token_header = remove_token_header;
+        self
+    }
+
+    pub fn with_token_sources(mut self, token_sources: Vec<TokenSource>) -> Self {
+        self.token_sources = token_sources;
+        self
+    }
+
+    pub fn with_identity_enricher(mut self, identity: Option<Arc<IdentityEnricher>>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Option<Arc<DecisionCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_denied_response(mut self, denied_response: DeniedResponseConfiguration) -> Self {
+        self.denied_response = denied_response;
+        self
+    }
+
+    pub fn with_time_constraints(
+        mut self,
+        leeway: Duration,
+        max_token_age: Option<Duration>,
+    ) -> Self {
+        self.leeway = leeway;
+        self.max_token_age = max_token_age;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Option<PolicySet>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn authorize(
+        &self,
+        assertion: &PrincipalAssertion,
+        target: &RequestTarget,
+    ) -> super::CheckResult<()> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+
+        match policy.evaluate(assertion, target) {
+            (PolicyAction::Allow, _) => Ok(()),
+            (PolicyAction::Deny, Some(rule)) => Err(CheckFailure::permission_denied(
+                CheckOutcome::PolicyDenied,
+                format!("denied by policy rule: {rule}"),
+            )),
+            (PolicyAction::Deny, None) => Err(CheckFailure::permission_denied(
+                CheckOutcome::PolicyDenied,
+                "denied by default policy",
+            )),
         }
+    }
+
+    /// Checks a service token against the allowlist, when one is configured.
+    /// User tokens are not affected.
+    fn check_service_grant(
+        &self,
+        assertion: &PrincipalAssertion,
+        target: &RequestTarget,
+    ) -> super::CheckResult<()> {
+        let PrincipalAssertion::Service(service) = assertion else {
+            return Ok(());
+        };
 
-        if self.exp_validation == TimeConstraintMode::Lax {
-            constraints.validate_exp = false;
+        if self.service_allowlist.is_empty() {
+            return Ok(());
         }
 
+        match self.service_allowlist.get(&service.common_name) {
+            Some(grants) if grants.iter().any(|grant| grant.matches(target)) => Ok(()),
+            Some(_) => Err(CheckFailure::permission_denied(
+                CheckOutcome::ServiceDenied,
+                format!(
+                    "service token {} has no grant for {}{}",
+                    service.common_name, target.authority, target.path
+                ),
+            )),
+            None => Err(CheckFailure::permission_denied(
+                CheckOutcome::ServiceDenied,
+                format!(
+                    "service token {} is not on the allowlist",
+                    service.common_name
+                ),
+            )),
+        }
+    }
+
+    /// Rejects tokens issued longer ago than the maximum token age. This is also
+    /// applied to cached decisions, which outlive the original validation.
+    fn check_token_age(&self, assertion: &PrincipalAssertion) -> super::CheckResult<()> {
+        let Some(max_token_age) = self.max_token_age else {
+            return Ok(());
+        };
+
+        let age = now_secs().saturating_sub(assertion.get_issued_at());
+
+        if age > max_token_age.as_secs() {
+            return Err(CheckFailure::unauthenticated(
+                CheckOutcome::InvalidClaims,
+                format!("token issued {age}s ago exceeds the maximum age"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn resolve_team(&self, token: &str) -> super::CheckResult<(&String, &String)> {
+        resolve_issuer_team(&self.issuers, token)
+    }
+
+    fn validate(
+        &self,
+        token: &str,
+        target: &RequestTarget,
+    ) -> super::CheckResult<PrincipalAssertion> {
+        let audiences = self
+            .aud_provider
+            .get_route_audiences(&target.authority, &target.path)
+            .ok_or_else(|| {
+                CheckFailure::permission_denied(
+                    CheckOutcome::NoRoute,
+                    format!("no audience route for {}{}", target.authority, target.path),
+                )
+            })?;
+
+        let assertion = match self.cache.as_ref().and_then(|cache| cache.get(token)) {
+            Some(assertion) => {
+                if !assertion
+                    .get_audiences()
+                    .iter()
+                    .any(|audience| audiences.contains(audience))
+                {
+                    return Err(CheckFailure::unauthenticated(
+                        CheckOutcome::JwtFailure,
+                        "failed CF JWT validation: InvalidAudience",
+                    ));
+                }
+
+                assertion
+            }
+            None => {
+                let assertion = self.verify(token, &audiences)?;
+
+                if let Some(cache) = &self.cache {
+                    cache.insert(token, &assertion);
+                }
+
+                assertion
+            }
+        };
+
+        // The token is valid from here, so failures keep its principal for auditing
+        match self
+            .check_token_age(&assertion)
+            .and_then(|_| self.check_service_grant(&assertion, target))
+            .and_then(|_| self.authorize(&assertion, target))
+        {
+            Ok(()) => Ok(assertion),
+            Err(failure) => Err(failure.with_assertion(assertion)),
+        }
+    }
+
+    fn verify(&self, token: &str, audiences: &[String]) -> super::CheckResult<PrincipalAssertion> {
+        let (issuer, team_name) = self.resolve_team(token)?;
+        let mut constraints = Validation::new(Algorithm::RS256);
+        constraints.set_audience(audiences);
+        constraints.set_issuer(&[issuer]);
+        let time_claims = apply_time_constraints(
+            &mut constraints,
+            &self.nbf_validation,
+            &self.exp_validation,
+            self.leeway,
+        );
+
         match self
             .validator
-            .validate_token(token, &self.default_team_name, &mut constraints)
+            .validate_token(token, team_name, &mut constraints)
         {
-            Ok(claims) => match PrincipalAssertion::from_claims_value(&claims.claims) {
-                Ok(assertion) => Ok(assertion),
-                Err(e) => Err(Status::invalid_argument(format!(
-                    "failed claims processing: {e}"
-                ))),
-            },
-            Err(e) => Err(Status::unauthenticated(format!(
-                "failed CF JWT validation: {e}"
-            ))),
+            Ok(claims) => {
+                check_time_claims(&claims.claims, &time_claims, now_secs()).map_err(|e| {
+                    CheckFailure::unauthenticated(
+                        CheckOutcome::JwtFailure,
+                        format!("failed CF JWT validation: {e}"),
+                    )
+                })?;
+
+                PrincipalAssertion::from_claims_value(&claims.claims).map_err(|e| {
+                    CheckFailure::unauthenticated(
+                        CheckOutcome::InvalidClaims,
+                        format!("failed claims processing: {e}"),
+                    )
+                })
+            }
+            Err(e) => {
+                let outcome = match e {
+                    KeyValidationError::UnknownKey(_) => CheckOutcome::UnknownKey,
+                    KeyValidationError::Invalid(_) => CheckOutcome::JwtFailure,
+                };
+
+                Err(CheckFailure::unauthenticated(
+                    outcome,
+                    format!("failed CF JWT validation: {e}"),
+                ))
+            }
+        }
+    }
+
+    /// Refreshes the issuing team's keys after a token checked at `checked_at`
+    /// named an unknown key id. Returns true if the token should be validated again.
+    async fn refresh_keys(&self, token: &str, checked_at: Instant) -> bool {
+        let Ok((_, team_name)) = self.resolve_team(token) else {
+            return false;
+        };
+
+        match self
+            .validator
+            .clone()
+            .refresh_team(team_name, checked_at)
+            .await
+        {
+            Some(Ok(rotated)) => {
+                if let (true, Some(cache)) = (rotated, &self.cache) {
+                    log::info!("Keys rotated, clearing decision cache");
+                    cache.clear();
+                }
+
+                true
+            }
+            Some(Err(_)) | None => false,
+        }
+    }
+
+    fn record_audit(
+        &self,
+        event: &CheckEvent,
+        assertion: Option<&PrincipalAssertion>,
+        target: &RequestTarget,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+
+        let route_audiences = self
+            .aud_provider
+            .get_route_audiences(&target.authority, &target.path)
+            .unwrap_or_default();
+
+        if !audit.record(&AuditRecord::new(event, assertion, &route_audiences)) {
+            self.metrics.record_audit_dropped(1);
+        }
+    }
+
+    /// Allows a request matched by a bypass rule without a token. Identity headers
+    /// supplied by the client are still removed, so upstreams can trust their absence.
+    fn allow_bypassed(
+        &self,
+        target: &RequestTarget,
+        client_headers: &HashMap<String, String>,
+        started: Instant,
+    ) -> CheckResponse {
+        let event = CheckEvent::new_bypassed(target);
+        event.log();
+        self.record_audit(&event, None, target);
+        self.metrics
+            .record_check(CheckOutcome::Bypassed, started.elapsed());
+
+        let mut builder = OkHttpResponseBuilder::new();
+        set_header(&mut builder, &self.headers, "Token-Type", "Anonymous");
+
+        let mut response = CheckResponse::with_status(Status::ok("request bypassed"));
+        response.set_http_response(builder);
+        response.set_dynamic_metadata(Some(new_anonymous_dynamic_metadata()));
+        remove_spoofed_headers(
+            &mut response,
+            client_headers,
+            &self.headers,
+            self.remove_token_header,
+        );
+        response
+    }
+
+    async fn validate_with_refresh(
+        &self,
+        token: &str,
+        target: &RequestTarget,
+    ) -> super::CheckResult<PrincipalAssertion> {
+        let checked_at = Instant::now();
+
+        match self.validate(token, target) {
+            Err(failure)
+                if failure.outcome == CheckOutcome::UnknownKey
+                    && self.refresh_keys(token, checked_at).await =>
+            {
+                self.validate(token, target)
+            }
+            result => result,
         }
     }
 }
@@ -73,30 +504,355 @@ impl CloudflareZeroTrustAuthorizationServer {
 #[tonic::async_trait]
 impl Authorization for CloudflareZeroTrustAuthorizationServer {
     async fn check(&self, request: Request<CheckRequest>) -> super::ExtAuthzResult {
+        let started = Instant::now();
         let check_request = request.into_inner();
         let client_headers = get_headers(&check_request)?;
+        let target = RequestTarget::from_check_request(&check_request)?;
 
-        let header = "cf-access-jwt-assertion".to_string();
-        match client_headers.get(&header) {
-            Some(value) => match self.validate(value) {
-                Ok(assertion) => {
-                    log::info!("Request passed validation");
-                    let mut builder = OkHttpResponseBuilder::new();
-                    assertion.mutate_response(&mut builder);
-
-                    let mut response = CheckResponse::with_status(Status::ok("token validated"));
-                    response.set_http_response(builder);
-                    Ok(Response::new(response))
-                }
-                Err(e) => {
-                    log::info!("Request failed validation: {}", e.to_string());
-                    Err(e)
+        if self.bypass.iter().any(|rule| rule.matches(&target)) {
+            return Ok(Response::new(self.allow_bypassed(
+                &target,
+                client_headers,
+                started,
+            )));
+        }
+
+        let token = get_token(client_headers, &self.token_sources);
+        let rate_limit = self.rate_limiter.as_ref().and_then(|limiter| {
+            limiter
+                .get_key(&target, client_headers)
+                .map(|key| (limiter, key))
+        });
+
+        let result = match token {
+            Some(_)
+                if rate_limit
+                    .as_ref()
+                    .is_some_and(|(limiter, key)| limiter.is_limited(key)) =>
+            {
+                Err(CheckFailure::rate_limited("too many failed validations"))
+            }
+            Some((source, value)) => self
+                .validate_with_refresh(value, &target)
+                .await
+                .map(|assertion| (source, value, assertion)),
+            None => Err(CheckFailure::unauthenticated(
+                CheckOutcome::MissingHeader,
+                "Missing CF JWT",
+            )),
+        };
+
+        if let (Err(failure), Some((limiter, key))) = (&result, &rate_limit) {
+            if failure.outcome.is_validation_failure() {
+                limiter.record_failure(key);
+            }
+        }
+
+        let assertion = result.as_ref().map(|(_, _, assertion)| assertion);
+        let event = CheckEvent::new(&target, token.map(|(source, _)| source), assertion);
+        event.log();
+
+        let audited_assertion = match &result {
+            Ok((_, _, assertion)) => Some(assertion),
+            Err(failure) => failure.assertion.as_deref(),
+        };
+        self.record_audit(&event, audited_assertion, &target);
+
+        match result {
+            Ok((source, token, assertion)) => {
+                self.metrics
+                    .record_check(CheckOutcome::Allowed, started.elapsed());
+                self.metrics.record_principal(&assertion);
+
+                let mut builder = OkHttpResponseBuilder::new();
+                assertion.mutate_response(&mut builder, &self.headers);
+                set_header(&mut builder, &self.headers, "Token-Source", source.as_str());
+
+                if let (PrincipalAssertion::User(user), Some(enricher)) =
+                    (&assertion, &self.identity)
+                {
+                    if let Some(identity) = enricher.clone().enrich(token, user).await {
+                        identity.mutate_response(&mut builder, &self.headers);
+                    }
                 }
-            },
-            None => {
-                log::warn!("Request missing JWT header");
-                Err(Status::invalid_argument("Missing CF JWT header"))
+
+                let mut response = CheckResponse::with_status(Status::ok("token validated"));
+                response.set_http_response(builder);
+                response.set_dynamic_metadata(Some(new_dynamic_metadata(&assertion)));
+                remove_spoofed_headers(
+                    &mut response,
+                    client_headers,
+                    &self.headers,
+                    self.remove_token_header,
+                );
+                Ok(Response::new(response))
+            }
+            Err(failure) => {
+                self.metrics
+                    .record_check(failure.outcome, started.elapsed());
+                Ok(Response::new(new_denied_response(
+                    failure.status,
+                    &self.denied_response,
+                )?))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::audience::schema::StaticAudienceProvider;
+    use crate::config::bootstrap::schema::{
+        CommonValidatorConfiguration, StaticTeamValidatorConfiguration, ValidatorConfiguration,
+    };
+    use crate::server::request::{new_target, new_user_assertion};
+    use crate::server::validator::{new_fetching_validator, new_validator};
+    use crate::testing::{new_signed_token, new_team_keys_json};
+    use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header};
+    use rust_cfzt_validator::api::TeamKeys;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use TimeConstraintMode::{Exact, Lax, Leeway, Strict};
+
+    fn new_token(claims: serde_json::Value) -> String {
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    /// Validates a token whose nbf and exp are offset from now, with a 120s leeway
+    fn is_valid(
+        nbf: TimeConstraintMode,
+        exp: TimeConstraintMode,
+        nbf_offset: i64,
+        exp_offset: i64,
+    ) -> bool {
+        let now = now_secs() as i64;
+        let token = new_token(json!({"nbf": now + nbf_offset, "exp": now + exp_offset}));
+
+        let mut constraints = Validation::new(Algorithm::HS256);
+        let time_claims =
+            apply_time_constraints(&mut constraints, &nbf, &exp, Duration::from_secs(120));
+
+        decode::<serde_json::Value>(&token, &DecodingKey::from_secret(b"secret"), &constraints)
+            .is_ok_and(|data| check_time_claims(&data.claims, &time_claims, now_secs()).is_ok())
+    }
+
+    #[test]
+    fn strict_keeps_library_defaults() {
+        assert!(is_valid(Strict, Strict, 1000, 30));
+        assert!(is_valid(Strict, Strict, -30, -30));
+        assert!(!is_valid(Strict, Strict, -30, -90));
+    }
+
+    #[test]
+    fn exact_allows_no_skew() {
+        assert!(is_valid(Exact, Exact, -30, 30));
+        assert!(!is_valid(Exact, Exact, 30, 30));
+        assert!(!is_valid(Exact, Exact, -30, -30));
+    }
+
+    #[test]
+    fn leeway_allows_configured_skew() {
+        assert!(is_valid(Leeway, Leeway, 90, -90));
+        assert!(!is_valid(Leeway, Leeway, 150, 30));
+        assert!(!is_valid(Leeway, Leeway, -30, -150));
+    }
+
+    #[test]
+    fn lax_skips_the_check() {
+        assert!(is_valid(Lax, Lax, 1000, -1000));
+        assert!(is_valid(Lax, Exact, 1000, 30));
+        assert!(is_valid(Exact, Lax, -30, -1000));
+    }
+
+    #[test]
+    fn leeway_on_one_claim_keeps_the_other_exact() {
+        assert!(is_valid(Leeway, Exact, 90, 30));
+        assert!(!is_valid(Leeway, Exact, -30, -30));
+        assert!(is_valid(Exact, Leeway, -30, -90));
+        assert!(!is_valid(Exact, Leeway, 30, 30));
+    }
+
+    #[test]
+    fn leeway_with_lax_claim_is_not_checked() {
+        assert!(is_valid(Leeway, Lax, 90, -1000));
+        assert!(!is_valid(Leeway, Lax, 150, 30));
+        assert!(is_valid(Lax, Leeway, 1000, -90));
+        assert!(!is_valid(Lax, Leeway, 1000, -150));
+    }
+
+    fn new_issuers(team_names: &[&str]) -> HashMap<String, String> {
+        team_names
+            .iter()
+            .map(|team_name| (get_team_issuer(team_name), team_name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn routes_tokens_to_the_issuing_team() {
+        let issuers = new_issuers(&["alpha", "beta"]);
+        let token = new_token(json!({"iss": "https://beta.cloudflareaccess.com"}));
+
+        let (issuer, team_name) = resolve_issuer_team(&issuers, &token).ok().unwrap();
+        assert_eq!(issuer, "https://beta.cloudflareaccess.com");
+        assert_eq!(team_name, "beta");
+    }
+
+    #[test]
+    fn rejects_unknown_issuers() {
+        let issuers = new_issuers(&["alpha"]);
+
+        for claims in [
+            json!({"iss": "https://gamma.cloudflareaccess.com"}),
+            json!({"iss": "https://alpha.cloudflareaccess.com.evil.example"}),
+        ] {
+            let failure = resolve_issuer_team(&issuers, &new_token(claims))
+                .err()
+                .unwrap();
+            assert_eq!(failure.outcome, CheckOutcome::JwtFailure);
+            assert_eq!(failure.status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let issuers = new_issuers(&["alpha"]);
+
+        let tokens = [
+            String::new(),
+            "not-a-jwt".to_string(),
+            "eyJhbGciOiJSUzI1NiJ9.e30".to_string(),
+            new_token(json!({"sub": "no-issuer"})),
+            new_token(json!({"iss": 42})),
+        ];
+
+        for token in tokens {
+            let failure = resolve_issuer_team(&issuers, &token).err().unwrap();
+            assert_eq!(failure.outcome, CheckOutcome::JwtFailure);
+            assert!(failure
+                .status
+                .message()
+                .starts_with("failed CF JWT decoding"));
+        }
+    }
+
+    fn new_static_validator() -> TeamSetValidator {
+        let configuration = ValidatorConfiguration::Team(
+            StaticTeamValidatorConfiguration {
+                team_name: "example".to_string(),
+                static_keys: Some(new_team_keys_json(&["key-1"])),
+            },
+            CommonValidatorConfiguration {
+                proxy_discovery: false,
+            },
+        );
+
+        new_validator(&configuration, Duration::ZERO, Duration::from_secs(1)).unwrap()
+    }
+
+    fn new_server(
+        validator: TeamSetValidator,
+        audiences: &[&str],
+    ) -> CloudflareZeroTrustAuthorizationServer {
+        let audiences = audiences
+            .iter()
+            .map(|audience| audience.to_string())
+            .collect();
+
+        CloudflareZeroTrustAuthorizationServer::new(
+            Arc::new(validator),
+            Arc::new(Box::new(StaticAudienceProvider::new(audiences))),
+            Arc::new(Metrics::new().unwrap()),
+            &["example".to_string()],
+            Strict,
+            Strict,
+        )
+    }
+
+    #[test]
+    fn rechecks_audiences_on_cache_hits() {
+        let cache = Arc::new(DecisionCache::new(10, Duration::from_secs(60)));
+        let target = new_target("GET", "app.example.com", "/");
+        let mut assertion = new_user_assertion();
+
+        if let PrincipalAssertion::User(user) = &mut assertion {
+            user.exp = now_secs() + 3600;
+        }
+
+        // The cached token is not a JWT, so only a cache hit can validate it
+        cache.insert("cached-token", &assertion);
+
+        let server =
+            new_server(new_static_validator(), &["app-aud"]).with_cache(Some(cache.clone()));
+        assert!(server.validate("cached-token", &target).is_ok());
+
+        let server = new_server(new_static_validator(), &["other-aud"]).with_cache(Some(cache));
+        let failure = server.validate("cached-token", &target).err().unwrap();
+        assert_eq!(failure.outcome, CheckOutcome::JwtFailure);
+        assert!(failure.status.message().ends_with("InvalidAudience"));
+    }
+
+    fn new_user_claims() -> serde_json::Value {
+        let now = now_secs();
+
+        json!({
+            "aud": ["app-aud"],
+            "email": "user@example.com",
+            "exp": now + 3600,
+            "iat": now,
+            "nbf": now,
+            "iss": get_team_issuer("example"),
+            "type": "app",
+            "identity_nonce": "nonce",
+            "sub": "user-id",
+            "country": "AU",
+        })
+    }
+
+    #[tokio::test]
+    async fn revalidates_unknown_keys_after_a_refresh() {
+        // The team publishes "key-2" once the first fetch has happened
+        let rotated = Arc::new(AtomicBool::new(false));
+        let published = rotated.clone();
+        let validator =
+            new_fetching_validator(&["example"], Duration::from_secs(60), move |team_name| {
+                let key_ids: &[&str] = match published.swap(true, Ordering::SeqCst) {
+                    false => &["key-1"],
+                    true => &["key-1", "key-2"],
+                };
+                TeamKeys::from_str(team_name, &new_team_keys_json(key_ids))
+            });
+        let cache = Arc::new(DecisionCache::new(10, Duration::from_secs(60)));
+        let server = new_server(validator, &["app-aud"]).with_cache(Some(cache.clone()));
+        let target = new_target("GET", "app.example.com", "/");
+
+        let cached_token = new_signed_token("key-1", &new_user_claims());
+        assert!(server
+            .validate_with_refresh(&cached_token, &target)
+            .await
+            .is_ok());
+        assert!(cache.get(&cached_token).is_some());
+
+        let token = new_signed_token("key-2", &new_user_claims());
+        assert!(server.validate(&token, &target).is_err());
+        assert!(server.validate_with_refresh(&token, &target).await.is_ok());
+
+        // The rotation invalidated every earlier decision
+        assert!(cache.get(&cached_token).is_none());
+
+        // A key the team never published stays unknown
+        let token = new_signed_token("key-3", &new_user_claims());
+        let failure = server
+            .validate_with_refresh(&token, &target)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(failure.outcome, CheckOutcome::UnknownKey);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use envoy_types::ext_authz::v3::pb::{
+    Authorization, CheckRequest, CheckResponse, HeaderValueOption, HttpResponse,
+};
+use envoy_types::pb::envoy::config::core::v3::{address, Address, SocketAddress};
+use envoy_types::pb::envoy::service::auth::v3::attribute_context::{
+    HttpRequest, Peer, Request as AttributeRequest,
+};
+use envoy_types::pb::envoy::service::auth::v3::AttributeContext;
+
+use super::extauthz::CloudflareZeroTrustAuthorizationServer;
+use crate::config::bootstrap::schema::ForwardAuthHeaders;
+
+fn get_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .map(|value| value.as_str())
+        .filter(|value| !value.is_empty())
+}
+
+/// The method, host and URI headers of a header set
+fn get_header_names(
+    forward_auth_headers: ForwardAuthHeaders,
+) -> (&'static str, &'static str, &'static str) {
+    match forward_auth_headers {
+        ForwardAuthHeaders::Forwarded => {
+            ("x-forwarded-method", "x-forwarded-host", "x-forwarded-uri")
+        }
+        ForwardAuthHeaders::Original => ("x-original-method", "host", "x-original-uri"),
+    }
+}
+
+/// Describes the original request, as forwarded by the proxy, in the form
+/// the Envoy check expects. Only the configured header set is read, since the
+/// proxy may pass the other set on from the client unchanged.
+/// Returns None if the proxy did not send the original URI.
+fn new_check_request(
+    method: &Method,
+    uri: &Uri,
+    request_headers: &HeaderMap,
+    forward_auth_headers: ForwardAuthHeaders,
+) -> Option<CheckRequest> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+
+    for (name, value) in request_headers {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+
+        headers
+            .entry(name.as_str().to_string())
+            .and_modify(|existing| {
+                existing.push(',');
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    let (method_name, host_name, uri_name) = get_header_names(forward_auth_headers);
+
+    let http = HttpRequest {
+        method: get_header(&headers, method_name)
+            .unwrap_or(method.as_str())
+            .to_string(),
+        host: get_header(&headers, host_name)
+            .or(uri.host())
+            .unwrap_or_default()
+            .to_string(),
+        path: get_header(&headers, uri_name)?.to_string(),
+        headers: headers.clone(),
+        ..Default::default()
+    };
+
+    // The proxy appends the address it received the request from, anything
+    // before that was supplied by the client
+    let source = get_header(&headers, "x-forwarded-for")
+        .or_else(|| get_header(&headers, "x-real-ip"))
+        .and_then(|addresses| addresses.rsplit(',').next())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+
+    Some(CheckRequest {
+        attributes: Some(AttributeContext {
+            source: Some(Peer {
+                address: Some(Address {
+                    address: Some(address::Address::SocketAddress(SocketAddress {
+                        address: source,
+                        ..Default::default()
+                    })),
+                }),
+                ..Default::default()
+            }),
+            request: Some(AttributeRequest {
+                http: Some(http),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+    })
+}
+
+fn append_headers(response: &mut Response, headers: &[HeaderValueOption]) {
+    for header in headers.iter().filter_map(|option| option.header.as_ref()) {
+        match (
+            HeaderName::try_from(header.key.as_str()),
+            HeaderValue::try_from(header.value.as_str()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                response.headers_mut().append(name, value);
+            }
+            _ => log::warn!("Skipping invalid forward auth header {}", header.key),
+        }
+    }
+}
+
+/// Converts a CheckResponse into the plain HTTP answer forward auth proxies
+/// expect: 200 with identity headers when allowed, the denied status otherwise.
+/// Proxies cannot be told to remove headers, so `headers_to_remove` is dropped.
+/// Instead the proxy must copy every identity header from the answer, replacing
+/// the client's values: `authResponseHeaders` in Traefik, `copy_headers` in Caddy,
+/// or `auth_request_set` with `proxy_set_header` for each header in nginx.
+fn new_forward_auth_response(check_response: CheckResponse) -> Response {
+    match check_response.http_response {
+        Some(HttpResponse::OkResponse(ok_response)) => {
+            let mut response = StatusCode::OK.into_response();
+            append_headers(&mut response, &ok_response.headers);
+            response
+        }
+        Some(HttpResponse::DeniedResponse(denied_response)) => {
+            let status = denied_response
+                .status
+                .and_then(|status| u16::try_from(status.code).ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .unwrap_or(StatusCode::FORBIDDEN);
+
+            let mut response = (status, Body::from(denied_response.body)).into_response();
+            append_headers(&mut response, &denied_response.headers);
+            response
+        }
+        None => match check_response.status.map(|status| status.code) {
+            Some(0) | None => StatusCode::OK.into_response(),
+            Some(_) => StatusCode::FORBIDDEN.into_response(),
+        },
+    }
+}
+
+#[derive(Clone)]
+struct ForwardAuthState {
+    server: Arc<CloudflareZeroTrustAuthorizationServer>,
+    forward_auth_headers: ForwardAuthHeaders,
+}
+
+async fn forward_auth(
+    State(state): State<ForwardAuthState>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let Some(check_request) =
+        new_check_request(&method, &uri, &headers, state.forward_auth_headers)
+    else {
+        let (_, _, uri_name) = get_header_names(state.forward_auth_headers);
+        log::warn!("Forward auth request denied without {uri_name}");
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    match state.server.check(tonic::Request::new(check_request)).await {
+        Ok(check_response) => new_forward_auth_response(check_response.into_inner()),
+        Err(status) => {
+            log::error!("Forward auth check failed: {}", status.message());
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Answers every path and method, so the proxy can use any URL for the auth request.
+pub fn new_forward_auth_router(
+    server: Arc<CloudflareZeroTrustAuthorizationServer>,
+    forward_auth_headers: ForwardAuthHeaders,
+) -> Router {
+    Router::new()
+        .fallback(forward_auth)
+        .with_state(ForwardAuthState {
+            server,
+            forward_auth_headers,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn get_http(check_request: &CheckRequest) -> &HttpRequest {
+        check_request
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.request.as_ref())
+            .and_then(|request| request.http.as_ref())
+            .unwrap()
+    }
+
+    const BOTH_SETS: &[(&str, &str)] = &[
+        ("host", "auth.internal"),
+        ("x-forwarded-method", "OPTIONS"),
+        ("x-forwarded-host", "forwarded.example.com"),
+        ("x-forwarded-uri", "/healthz"),
+        ("x-original-method", "DELETE"),
+        ("x-original-uri", "/admin?x=1"),
+    ];
+
+    #[test]
+    fn reads_forwarded_headers() {
+        let headers = new_headers(BOTH_SETS);
+        let check_request = new_check_request(
+            &Method::GET,
+            &Uri::from_static("/auth"),
+            &headers,
+            ForwardAuthHeaders::Forwarded,
+        )
+        .unwrap();
+        let http = get_http(&check_request);
+
+        assert_eq!(http.method, "OPTIONS");
+        assert_eq!(http.host, "forwarded.example.com");
+        assert_eq!(http.path, "/healthz");
+    }
+
+    #[test]
+    fn reads_original_headers() {
+        let headers = new_headers(BOTH_SETS);
+        let check_request = new_check_request(
+            &Method::GET,
+            &Uri::from_static("/auth"),
+            &headers,
+            ForwardAuthHeaders::Original,
+        )
+        .unwrap();
+        let http = get_http(&check_request);
+
+        assert_eq!(http.method, "DELETE");
+        assert_eq!(http.host, "auth.internal");
+        assert_eq!(http.path, "/admin?x=1");
+    }
+
+    #[test]
+    fn never_falls_back_to_the_other_set() {
+        let headers = new_headers(&[
+            ("host", "app.example.com"),
+            ("x-forwarded-method", "OPTIONS"),
+            ("x-forwarded-host", "other.example.com"),
+            ("x-forwarded-uri", "/healthz"),
+        ]);
+
+        assert!(new_check_request(
+            &Method::GET,
+            &Uri::from_static("/auth"),
+            &headers,
+            ForwardAuthHeaders::Original,
+        )
+        .is_none());
+
+        let headers = new_headers(&[
+            ("x-original-method", "OPTIONS"),
+            ("x-original-uri", "/healthz"),
+        ]);
+
+        assert!(new_check_request(
+            &Method::GET,
+            &Uri::from_static("/auth"),
+            &headers,
+            ForwardAuthHeaders::Forwarded,
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn defaults_method_and_host_to_the_auth_request() {
+        let headers = new_headers(&[("x-forwarded-uri", "/app")]);
+        let check_request = new_check_request(
+            &Method::POST,
+            &Uri::from_static("http://auth.internal/auth"),
+            &headers,
+            ForwardAuthHeaders::Forwarded,
+        )
+        .unwrap();
+        let http = get_http(&check_request);
+
+        assert_eq!(http.method, "POST");
+        assert_eq!(http.host, "auth.internal");
+        assert_eq!(http.path, "/app");
+    }
+
+    #[test]
+    fn reads_source_from_the_proxy_hop() {
+        let headers = new_headers(&[
+            ("x-forwarded-uri", "/"),
+            ("x-forwarded-for", "1.1.1.1, 2.2.2.2"),
+            ("x-real-ip", "3.3.3.3"),
+        ]);
+        let check_request = new_check_request(
+            &Method::GET,
+            &Uri::from_static("/auth"),
+            &headers,
+            ForwardAuthHeaders::Forwarded,
+        )
+        .unwrap();
+        let address = check_request
+            .attributes
+            .and_then(|attributes| attributes.source)
+            .and_then(|source| source.address)
+            .and_then(|address| address.address);
+
+        match address {
+            Some(address::Address::SocketAddress(socket)) => {
+                assert_eq!(socket.address, "2.2.2.2")
+            }
+            _ => panic!("source address missing"),
+        }
+    }
+}
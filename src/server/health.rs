@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use envoy_types::ext_authz::v3::pb::AuthorizationServer;
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use super::extauthz::CloudflareZeroTrustAuthorizationServer;
+
+pub const AUTHORIZATION_SERVICE_NAME: &str =
+    <AuthorizationServer<CloudflareZeroTrustAuthorizationServer> as NamedService>::NAME;
+
+/// A team's most recent successful key synchronisation, and whether
+/// synchronisation has failed since
+struct TeamSync {
+    synced_at: Instant,
+    failing: bool,
+}
+
+/// Tracks whether the validator holds a usable key set, and reports
+/// NOT_SERVING once a team's key synchronisation has been failing and its
+/// last successful synchronisation is older than the configured threshold.
+pub struct KeyHealthMonitor {
+    reporter: HealthReporter,
+    failure_threshold: Duration,
+    ready: AtomicBool,
+    teams: Mutex<HashMap<String, TeamSync>>,
+}
+
+impl KeyHealthMonitor {
+    pub fn new(reporter: HealthReporter, failure_threshold: Duration) -> Self {
+        KeyHealthMonitor {
+            reporter,
+            failure_threshold,
+            ready: AtomicBool::new(false),
+            teams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_not_ready(&self) {
+        self.ready.store(false, Ordering::SeqCst);
+    }
+
+    /// Records the teams whose keys were fetched at startup as synchronised
+    pub fn register_teams(&self, team_names: &[String]) {
+        for team_name in team_names {
+            self.record_sync_at(team_name, true, Instant::now());
+        }
+    }
+
+    /// Records a scheduled or on-demand synchronisation of a team's keys
+    pub fn record_sync<T, E>(&self, team_name: &str, result: &Result<T, E>) {
+        self.record_sync_at(team_name, result.is_ok(), Instant::now());
+    }
+
+    fn record_sync_at(&self, team_name: &str, succeeded: bool, now: Instant) {
+        let mut teams = self.teams.lock().unwrap_or_else(PoisonError::into_inner);
+        let team = teams.entry(team_name.to_string()).or_insert(TeamSync {
+            synced_at: now,
+            failing: false,
+        });
+
+        if succeeded {
+            team.synced_at = now;
+        }
+
+        team.failing = !succeeded;
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.is_healthy_at(Instant::now())
+    }
+
+    fn is_healthy_at(&self, now: Instant) -> bool {
+        if !self.ready.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        self.teams
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .all(|team| {
+                !team.failing
+                    || now.saturating_duration_since(team.synced_at) <= self.failure_threshold
+            })
+    }
+
+    pub async fn report(&self) {
+        let status = match self.is_healthy() {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+
+        self.reporter.set_service_status("", status).await;
+        self.reporter
+            .set_service_status(AUTHORIZATION_SERVICE_NAME, status)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic_health::pb::health_check_response::ServingStatus as ReportedStatus;
+    use tonic_health::pb::health_server::Health;
+    use tonic_health::pb::HealthCheckRequest;
+    use tonic_health::server::HealthService;
+
+    const THRESHOLD: Duration = Duration::from_secs(3600);
+
+    fn new_monitor() -> (KeyHealthMonitor, HealthService) {
+        let reporter = HealthReporter::new();
+        let service = HealthService::from_health_reporter(reporter.clone());
+        let monitor = KeyHealthMonitor::new(reporter, THRESHOLD);
+        monitor.register_teams(&["alpha".to_string(), "beta".to_string()]);
+        (monitor, service)
+    }
+
+    async fn get_reported_status(service: &HealthService) -> ReportedStatus {
+        let request = tonic::Request::new(HealthCheckRequest {
+            service: AUTHORIZATION_SERVICE_NAME.to_string(),
+        });
+
+        service.check(request).await.unwrap().into_inner().status()
+    }
+
+    #[tokio::test]
+    async fn reports_not_serving_until_ready() {
+        let (monitor, service) = new_monitor();
+
+        monitor.report().await;
+        assert_eq!(
+            get_reported_status(&service).await,
+            ReportedStatus::NotServing
+        );
+
+        monitor.set_ready();
+        monitor.report().await;
+        assert_eq!(get_reported_status(&service).await, ReportedStatus::Serving);
+
+        monitor.set_not_ready();
+        monitor.report().await;
+        assert_eq!(
+            get_reported_status(&service).await,
+            ReportedStatus::NotServing
+        );
+    }
+
+    #[test]
+    fn measures_staleness_from_the_last_successful_sync() {
+        let (monitor, _) = new_monitor();
+        let synced_at = Instant::now();
+        monitor.set_ready();
+        monitor.record_sync_at("alpha", true, synced_at);
+
+        // A failure shortly before the threshold does not restart the clock
+        let failed_at = synced_at + THRESHOLD - Duration::from_secs(60);
+        monitor.record_sync_at("alpha", false, failed_at);
+        assert!(monitor.is_healthy_at(failed_at));
+        assert!(monitor.is_healthy_at(synced_at + THRESHOLD));
+        assert!(!monitor.is_healthy_at(synced_at + THRESHOLD + Duration::from_secs(1)));
+
+        monitor.record_sync_at("alpha", false, synced_at + THRESHOLD * 2);
+        assert!(!monitor.is_healthy_at(synced_at + THRESHOLD * 2));
+    }
+
+    #[test]
+    fn recovers_once_a_sync_succeeds() {
+        let (monitor, _) = new_monitor();
+        let synced_at = Instant::now();
+        monitor.set_ready();
+        monitor.record_sync_at("alpha", true, synced_at);
+        monitor.record_sync_at("alpha", false, synced_at + Duration::from_secs(60));
+
+        let recovered_at = synced_at + THRESHOLD * 2;
+        assert!(!monitor.is_healthy_at(recovered_at));
+
+        monitor.record_sync_at("alpha", true, recovered_at);
+        assert!(monitor.is_healthy_at(recovered_at));
+        assert!(monitor.is_healthy_at(recovered_at + THRESHOLD * 2));
+    }
+
+    #[test]
+    fn reports_any_stale_team() {
+        let (monitor, _) = new_monitor();
+        let synced_at = Instant::now();
+        monitor.set_ready();
+        monitor.record_sync_at("alpha", true, synced_at);
+        monitor.record_sync_at("beta", true, synced_at);
+        monitor.record_sync_at("beta", false, synced_at + Duration::from_secs(60));
+
+        let checked_at = synced_at + THRESHOLD * 2;
+        monitor.record_sync_at("alpha", true, checked_at);
+        assert!(!monitor.is_healthy_at(checked_at));
+    }
+}
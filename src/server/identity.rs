@@ -0,0 +1,277 @@
+use std::sync::Arc;
+
+use envoy_types::ext_authz::v3::OkHttpResponseBuilder;
+use jnt::types::{EmptyResult, StdResult};
+use serde::Deserialize;
+
+use super::cache::ExpiringCache;
+use super::request::UserAssertion;
+use super::response::{set_header, ResponseMutator};
+use crate::config::bootstrap::schema::HeaderConfiguration;
+
+#[derive(Clone, Deserialize)]
+pub struct IdentityGroup {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct IdentityProvider {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default, rename = "type")]
+    pub typ: String,
+}
+
+/// The subset of the Access get-identity response forwarded upstream.
+#[derive(Clone, Deserialize)]
+pub struct Identity {
+    #[serde(default)]
+    pub groups: Vec<IdentityGroup>,
+    pub idp: Option<IdentityProvider>,
+}
+
+impl ResponseMutator for Identity {
+    fn mutate_response(
+        &self,
+        builder: &mut OkHttpResponseBuilder,
+        headers: &HeaderConfiguration,
+    ) -> EmptyResult {
+        let names: Vec<&str> = self
+            .groups
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect();
+        let ids: Vec<&str> = self.groups.iter().map(|group| group.id.as_str()).collect();
+
+        set_header(builder, headers, "Groups", &names.join(","));
+        set_header(builder, headers, "Group-Ids", &ids.join(","));
+
+        if let Some(idp) = &self.idp {
+            set_header(builder, headers, "Idp-Id", &idp.id);
+            set_header(builder, headers, "Idp-Type", &idp.typ);
+        }
+
+        Ok(())
+    }
+}
+
+/// Looks up group membership for validated users through the team's
+/// `/cdn-cgi/access/get-identity` endpoint, caching results per identity nonce.
+pub struct IdentityEnricher {
+    agent: ureq::Agent,
+    base_url: Option<String>,
+    identities: ExpiringCache<String, Identity>,
+}
+
+impl IdentityEnricher {
+    pub fn new(agent: ureq::Agent, base_url: Option<String>, max_entries: usize) -> Self {
+        IdentityEnricher {
+            agent,
+            base_url,
+            identities: ExpiringCache::new(max_entries),
+        }
+    }
+
+    fn fetch(&self, token: &str, assertion: &UserAssertion) -> StdResult<Identity> {
+        let base_url = self.base_url.as_deref().unwrap_or(&assertion.iss);
+        let url = format!(
+            "{}/cdn-cgi/access/get-identity",
+            base_url.trim_end_matches('/')
+        );
+
+        let body = self
+            .agent
+            .get(&url)
+            .header("Cookie", &format!("CF_Authorization={token}"))
+            .call()?
+            .body_mut()
+            .read_to_string()?;
+
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Returns the identity for a validated user, or None if it could not be fetched.
+    /// The blocking request runs off the async runtime.
+    pub async fn enrich(
+        self: Arc<Self>,
+        token: &str,
+        assertion: &UserAssertion,
+    ) -> Option<Identity> {
+        if let Some(identity) = self.identities.get(&assertion.nonce) {
+            return Some(identity);
+        }
+
+        let token = token.to_string();
+        let assertion = assertion.clone();
+        let result = tokio::task::spawn_blocking(move || match self.fetch(&token, &assertion) {
+            Ok(identity) => {
+                self.identities.insert(
+                    assertion.nonce.to_string(),
+                    identity.clone(),
+                    assertion.exp,
+                );
+                Ok(identity)
+            }
+            Err(e) => Err(e.to_string()),
+        })
+        .await;
+
+        match result {
+            Ok(Ok(identity)) => Some(identity),
+            Ok(Err(e)) => {
+                log::warn!("Failed to fetch identity for enrichment: {e}");
+                None
+            }
+            Err(e) => {
+                log::error!("Identity enrichment task failed: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::cache::now_secs;
+    use crate::server::request::{new_user_assertion, PrincipalAssertion};
+    use crate::testing::StandInServer;
+    use envoy_types::ext_authz::v3::pb::{CheckResponse, HttpResponse};
+    use envoy_types::ext_authz::v3::CheckResponseExt;
+    use tonic::Status;
+
+    const IDENTITY: &str = r#"{
+        "email": "user@example.com",
+        "groups": [{"id": "g1", "name": "admins"}, {"id": "g2", "name": "staff"}],
+        "idp": {"id": "idp1", "type": "okta"}
+    }"#;
+
+    fn new_user(nonce: &str, exp: u64) -> UserAssertion {
+        let PrincipalAssertion::User(mut user) = new_user_assertion() else {
+            unreachable!()
+        };
+
+        user.nonce = nonce.to_string();
+        user.exp = exp;
+        user
+    }
+
+    fn new_enricher(server: &StandInServer) -> Arc<IdentityEnricher> {
+        Arc::new(IdentityEnricher::new(
+            ureq::Agent::new_with_defaults(),
+            Some(format!("{}/", server.url)),
+            10,
+        ))
+    }
+
+    fn get_headers(identity: &Identity) -> Vec<(String, String)> {
+        let mut builder = OkHttpResponseBuilder::new();
+        identity
+            .mutate_response(&mut builder, &HeaderConfiguration::default())
+            .unwrap();
+
+        let mut response = CheckResponse::with_status(Status::ok(""));
+        response.set_http_response(builder);
+
+        let Some(HttpResponse::OkResponse(ok_response)) = response.http_response else {
+            unreachable!()
+        };
+
+        ok_response
+            .headers
+            .into_iter()
+            .filter_map(|option| option.header)
+            .map(|header| (header.key.to_lowercase(), header.value))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fetches_identities_with_the_token() {
+        let server = StandInServer::start(|_| (200, IDENTITY.to_string()));
+        let user = new_user("nonce-1", now_secs() + 60);
+
+        let identity = new_enricher(&server).enrich("token", &user).await.unwrap();
+
+        assert_eq!(identity.groups.len(), 2);
+        assert_eq!(identity.idp.as_ref().unwrap().typ, "okta");
+
+        let requests = server.get_requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /cdn-cgi/access/get-identity HTTP/1.1"));
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("cookie: cf_authorization=token"));
+    }
+
+    #[tokio::test]
+    async fn skips_enrichment_when_the_fetch_fails() {
+        let server = StandInServer::start(|_| (403, "{}".to_string()));
+        let enricher = new_enricher(&server);
+        let user = new_user("nonce-1", now_secs() + 60);
+
+        assert!(enricher.clone().enrich("token", &user).await.is_none());
+        assert!(enricher.enrich("token", &user).await.is_none());
+
+        // Failures are not cached
+        assert_eq!(server.get_requests().len(), 2);
+
+        let server = StandInServer::start(|_| (200, "not json".to_string()));
+        assert!(new_enricher(&server).enrich("token", &user).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn caches_identities_per_nonce_until_expiry() {
+        let server = StandInServer::start(|_| (200, IDENTITY.to_string()));
+        let enricher = new_enricher(&server);
+        let now = now_secs();
+
+        let user = new_user("nonce-1", now + 60);
+        assert!(enricher.clone().enrich("token", &user).await.is_some());
+        assert!(enricher.clone().enrich("token", &user).await.is_some());
+        assert_eq!(server.get_requests().len(), 1);
+
+        let user = new_user("nonce-2", now + 60);
+        assert!(enricher.clone().enrich("token", &user).await.is_some());
+        assert_eq!(server.get_requests().len(), 2);
+
+        // An identity is never held past the token's expiry
+        let user = new_user("nonce-3", now.saturating_sub(1));
+        assert!(enricher.clone().enrich("token", &user).await.is_some());
+        assert!(enricher.enrich("token", &user).await.is_some());
+        assert_eq!(server.get_requests().len(), 4);
+    }
+
+    #[test]
+    fn adds_group_and_idp_headers() {
+        let identity: Identity = serde_json::from_str(IDENTITY).unwrap();
+
+        assert_eq!(
+            get_headers(&identity),
+            vec![
+                (
+                    "x-cfzt-extauthz-groups".to_string(),
+                    "admins,staff".to_string()
+                ),
+                ("x-cfzt-extauthz-group-ids".to_string(), "g1,g2".to_string()),
+                ("x-cfzt-extauthz-idp-id".to_string(), "idp1".to_string()),
+                ("x-cfzt-extauthz-idp-type".to_string(), "okta".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn omits_idp_headers_without_an_idp() {
+        let identity: Identity = serde_json::from_str(r#"{"groups": []}"#).unwrap();
+
+        assert_eq!(
+            get_headers(&identity),
+            vec![
+                ("x-cfzt-extauthz-groups".to_string(), String::new()),
+                ("x-cfzt-extauthz-group-ids".to_string(), String::new()),
+            ]
+        );
+    }
+}
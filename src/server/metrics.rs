@@ -0,0 +1,258 @@
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use jnt::types::StdResult;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use super::outcome::CheckOutcome;
+use super::ratelimit::RateLimiter;
+use super::request::PrincipalAssertion;
+
+/// Holds the Prometheus registry and every metric exported by the service.
+pub struct Metrics {
+    registry: Registry,
+    checks: IntCounterVec,
+    check_duration: Histogram,
+    principals: IntCounterVec,
+    key_syncs: IntCounterVec,
+    key_sync_last_success: GaugeVec,
+    audit_dropped: IntCounter,
+    rate_limit_sources: IntGauge,
+    rate_limit_limited: IntGauge,
+    rate_limiter: OnceLock<Arc<RateLimiter>>,
+}
+
+impl Metrics {
+    pub fn new() -> StdResult<Self> {
+        let registry = Registry::new_custom(Some("extauthz".to_string()), None)?;
+
+        let checks = IntCounterVec::new(
+            Opts::new("checks_total", "Authorization checks by outcome"),
+            &["outcome"],
+        )?;
+        let check_duration = Histogram::with_opts(HistogramOpts::new(
+            "check_duration_seconds",
+            "Authorization check latency",
+        ))?;
+        let principals = IntCounterVec::new(
+            Opts::new("principals_total", "Allowed checks by principal type"),
+            &["type"],
+        )?;
+        let key_syncs = IntCounterVec::new(
+            Opts::new(
+                "key_syncs_total",
+                "Key synchronisation attempts by team and result",
+            ),
+            &["team", "result"],
+        )?;
+        let key_sync_last_success = GaugeVec::new(
+            Opts::new(
+                "key_sync_last_success_timestamp_seconds",
+                "Unix time of the last successful key synchronisation by team",
+            ),
+            &["team"],
+        )?;
+
+        let audit_dropped = IntCounter::new(
+            "audit_records_dropped_total",
+            "Audit records dropped because the buffer was full or writing failed",
+        )?;
+
+        let rate_limit_sources = IntGauge::new(
+            "rate_limit_sources",
+            "Clients tracked by the failed validation rate limiter",
+        )?;
+        let rate_limit_limited = IntGauge::new(
+            "rate_limit_limited_sources",
+            "Clients currently denied by the failed validation rate limiter",
+        )?;
+
+        registry.register(Box::new(checks.clone()))?;
+        registry.register(Box::new(check_duration.clone()))?;
+        registry.register(Box::new(principals.clone()))?;
+        registry.register(Box::new(key_syncs.clone()))?;
+        registry.register(Box::new(key_sync_last_success.clone()))?;
+        registry.register(Box::new(audit_dropped.clone()))?;
+        registry.register(Box::new(rate_limit_sources.clone()))?;
+        registry.register(Box::new(rate_limit_limited.clone()))?;
+
+        for outcome in CheckOutcome::ALL {
+            checks.with_label_values(&[outcome.as_str()]);
+        }
+
+        for principal_type in ["user", "service"] {
+            principals.with_label_values(&[principal_type]);
+        }
+
+        Ok(Metrics {
+            registry,
+            checks,
+            check_duration,
+            principals,
+            key_syncs,
+            key_sync_last_success,
+            audit_dropped,
+            rate_limit_sources,
+            rate_limit_limited,
+            rate_limiter: OnceLock::new(),
+        })
+    }
+
+    pub fn record_check(&self, outcome: CheckOutcome, duration: Duration) {
+        self.checks.with_label_values(&[outcome.as_str()]).inc();
+        self.check_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_principal(&self, assertion: &PrincipalAssertion) {
+        let principal_type = match assertion {
+            PrincipalAssertion::User(_) => "user",
+            PrincipalAssertion::Service(_) => "service",
+        };
+
+        self.principals.with_label_values(&[principal_type]).inc();
+    }
+
+    pub fn record_sync<T>(&self, team_name: &str, result: &StdResult<T>) {
+        match result {
+            Ok(_) => {
+                self.key_syncs
+                    .with_label_values(&[team_name, "success"])
+                    .inc();
+
+                if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                    self.key_sync_last_success
+                        .with_label_values(&[team_name])
+                        .set(now.as_secs_f64());
+                }
+            }
+            Err(_) => {
+                self.key_syncs
+                    .with_label_values(&[team_name, "failure"])
+                    .inc();
+            }
+        }
+    }
+
+    /// Exports every key synchronisation series for the teams, so that a team
+    /// which never synchronises still shows up.
+    pub fn register_teams(&self, team_names: &[String]) {
+        for team_name in team_names {
+            for result in ["success", "failure"] {
+                self.key_syncs.with_label_values(&[team_name, result]);
+            }
+        }
+    }
+
+    pub fn record_audit_dropped(&self, count: u64) {
+        self.audit_dropped.inc_by(count);
+    }
+
+    /// Exports the limiter's state, which is read whenever metrics are encoded.
+    pub fn set_rate_limiter(&self, rate_limiter: Arc<RateLimiter>) {
+        self.rate_limiter.set(rate_limiter).ok();
+    }
+
+    pub fn encode(&self) -> StdResult<String> {
+        if let Some(rate_limiter) = self.rate_limiter.get() {
+            let (sources, limited) = rate_limiter.get_state();
+            self.rate_limit_sources.set(sources as i64);
+            self.rate_limit_limited.set(limited as i64);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+async fn serve_metrics(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.encode() {
+        Ok(body) => ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response(),
+        Err(e) => {
+            log::error!("Failed to encode metrics: {e}");
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub fn new_metrics_router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .with_state(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_user_assertion;
+
+    /// Returns the value of a series in the text exposition
+    fn get_value(exposition: &str, series: &str) -> Option<f64> {
+        exposition
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("extauthz_{series} ")))
+            .map(|value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn exports_check_outcomes_and_principals() {
+        let metrics = Metrics::new().unwrap();
+        metrics.record_check(CheckOutcome::Allowed, Duration::from_millis(5));
+        metrics.record_check(CheckOutcome::Allowed, Duration::from_millis(5));
+        metrics.record_check(CheckOutcome::JwtFailure, Duration::from_millis(5));
+        metrics.record_principal(&new_user_assertion());
+
+        let exposition = metrics.encode().unwrap();
+        let value = |series| get_value(&exposition, series);
+        assert_eq!(value(r#"checks_total{outcome="allowed"}"#), Some(2.0));
+        assert_eq!(value(r#"checks_total{outcome="jwt_failure"}"#), Some(1.0));
+        assert_eq!(value(r#"checks_total{outcome="unknown_key"}"#), Some(0.0));
+        assert_eq!(value(r#"principals_total{type="user"}"#), Some(1.0));
+        assert_eq!(value(r#"principals_total{type="service"}"#), Some(0.0));
+        assert_eq!(value("check_duration_seconds_count"), Some(3.0));
+    }
+
+    #[test]
+    fn exports_key_syncs_by_team_and_result() {
+        let metrics = Metrics::new().unwrap();
+        metrics.register_teams(&["alpha".to_string(), "beta".to_string()]);
+        let synced: StdResult<bool> = Ok(true);
+        let failed: StdResult<bool> = Err("unavailable".into());
+        metrics.record_sync("alpha", &synced);
+        metrics.record_sync("alpha", &failed);
+        metrics.record_sync("beta", &failed);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        let exposition = metrics.encode().unwrap();
+        let value = |series| get_value(&exposition, series);
+        assert_eq!(
+            value(r#"key_syncs_total{result="success",team="alpha"}"#),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(r#"key_syncs_total{result="failure",team="alpha"}"#),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(r#"key_syncs_total{result="success",team="beta"}"#),
+            Some(0.0)
+        );
+        assert_eq!(
+            value(r#"key_syncs_total{result="failure",team="beta"}"#),
+            Some(1.0)
+        );
+
+        // Only teams that synchronised have a last success
+        let last_success =
+            value(r#"key_sync_last_success_timestamp_seconds{team="alpha"}"#).unwrap();
+        assert!((last_success - now.as_secs_f64()).abs() < 60.0);
+        assert_eq!(
+            value(r#"key_sync_last_success_timestamp_seconds{team="beta"}"#),
+            None
+        );
+    }
+}
@@ -1,10 +1,20 @@
 use envoy_types::ext_authz::v3::pb::CheckResponse;
 use tonic::{Response, Status};
 
+pub mod audit;
+pub mod cache;
+pub mod event;
 pub mod extauthz;
+pub mod forward;
+pub mod health;
+pub mod identity;
+pub mod metrics;
+pub mod outcome;
+pub mod ratelimit;
 pub mod request;
 pub mod response;
 pub mod validator;
 
 type StatusResult<T> = Result<T, Status>;
+type CheckResult<T> = Result<T, outcome::CheckFailure>;
 type ExtAuthzResult = StatusResult<Response<CheckResponse>>;
@@ -0,0 +1,94 @@
+use tonic::Status;
+
+use super::request::PrincipalAssertion;
+
+/// The classification of a check decision, used for telemetry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckOutcome {
+    Allowed,
+    Bypassed,
+    MissingHeader,
+    InvalidClaims,
+    JwtFailure,
+    UnknownKey,
+    NoRoute,
+    PolicyDenied,
+    ServiceDenied,
+    RateLimited,
+}
+
+impl CheckOutcome {
+    pub const ALL: [CheckOutcome; 10] = [
+        Self::Allowed,
+        Self::Bypassed,
+        Self::MissingHeader,
+        Self::InvalidClaims,
+        Self::JwtFailure,
+        Self::UnknownKey,
+        Self::NoRoute,
+        Self::PolicyDenied,
+        Self::ServiceDenied,
+        Self::RateLimited,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allowed => "allowed",
+            Self::Bypassed => "bypassed",
+            Self::MissingHeader => "missing_header",
+            Self::InvalidClaims => "invalid_claims",
+            Self::JwtFailure => "jwt_failure",
+            Self::UnknownKey => "unknown_key",
+            Self::NoRoute => "no_route",
+            Self::PolicyDenied => "policy_denied",
+            Self::ServiceDenied => "service_denied",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+
+    /// Returns true for failures caused by the token itself, which count
+    /// towards the client's rate limit. Unknown keys are left out, since valid
+    /// tokens carry them until the rotated keys are fetched.
+    pub fn is_validation_failure(&self) -> bool {
+        matches!(self, Self::InvalidClaims | Self::JwtFailure)
+    }
+}
+
+pub struct CheckFailure {
+    pub outcome: CheckOutcome,
+    pub status: Status,
+    /// The validated token's principal, for failures after validation
+    pub assertion: Option<Box<PrincipalAssertion>>,
+}
+
+impl CheckFailure {
+    pub fn unauthenticated(outcome: CheckOutcome, message: impl Into<String>) -> Self {
+        CheckFailure {
+            outcome,
+            status: Status::unauthenticated(message),
+            assertion: None,
+        }
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        CheckFailure {
+            outcome: CheckOutcome::RateLimited,
+            status: Status::resource_exhausted(message),
+            assertion: None,
+        }
+    }
+
+    pub fn permission_denied(outcome: CheckOutcome, message: impl Into<String>) -> Self {
+        CheckFailure {
+            outcome,
+            status: Status::permission_denied(message),
+            assertion: None,
+        }
+    }
+
+    /// Attaches the principal of a token that was validated before the check failed
+    pub fn with_assertion(mut self, assertion: PrincipalAssertion) -> Self {
+        self.assertion = Some(Box::new(assertion));
+        self
+    }
+}
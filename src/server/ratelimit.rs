@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use super::request::RequestTarget;
+use crate::config::bootstrap::schema::{RateLimitConfiguration, RateLimitKey};
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Counts failed validations per client in token buckets, so that clients
+/// repeatedly sending bad tokens can be denied before any verification runs.
+pub struct RateLimiter {
+    key: RateLimitKey,
+    burst: f64,
+    refill_per_second: f64,
+    max_sources: usize,
+    trusted_hops: usize,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(configuration: &RateLimitConfiguration) -> Self {
+        RateLimiter {
+            key: configuration.key,
+            burst: f64::from(configuration.burst),
+            refill_per_second: configuration.refill_per_second,
+            max_sources: configuration.max_sources,
+            trusted_hops: configuration.trusted_hops.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, TokenBucket>> {
+        self.buckets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_second).min(self.burst);
+        bucket.updated_at = now;
+    }
+
+    /// Returns the client a request is counted against. X-Forwarded-For is read
+    /// from the right, skipping the entries appended by trusted proxies, since
+    /// anything further left is client supplied. Returns None when the request
+    /// has no client to count against, rather than sharing one bucket.
+    pub fn get_key(
+        &self,
+        target: &RequestTarget,
+        headers: &HashMap<String, String>,
+    ) -> Option<String> {
+        let header = match self.key {
+            RateLimitKey::Source => None,
+            RateLimitKey::XForwardedFor => headers.get("x-forwarded-for").and_then(|addresses| {
+                let addresses: Vec<&str> = addresses.split(',').collect();
+                let index = addresses.len().saturating_sub(self.trusted_hops);
+                addresses.get(index).copied()
+            }),
+            RateLimitKey::CfConnectingIp => headers.get("cf-connecting-ip").map(|s| s.as_str()),
+        };
+
+        let key = header
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .unwrap_or(target.source_address.trim());
+
+        if key.is_empty() {
+            log::debug!(
+                "No client address for {}, not rate limiting",
+                target.authority
+            );
+            return None;
+        }
+
+        Some(key.to_string())
+    }
+
+    /// Returns true if the client has no failed validations left.
+    pub fn is_limited(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+
+        match buckets.get_mut(key) {
+            Some(bucket) => {
+                self.refill(bucket, now);
+                bucket.tokens < 1.0
+            }
+            None => false,
+        }
+    }
+
+    /// Takes a token from the client's bucket. New clients are not tracked
+    /// while the limiter holds its maximum number of buckets, until the next
+    /// sweep makes room.
+    pub fn record_failure(&self, key: &str) {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+
+        if !buckets.contains_key(key) && buckets.len() >= self.max_sources {
+            log::debug!("Rate limiter is full, not tracking {key}");
+            return;
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+
+        self.refill(bucket, now);
+        bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    }
+
+    /// Forgets the buckets that have refilled, returning how many were removed.
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        let tracked = buckets.len();
+
+        buckets.retain(|_, bucket| {
+            self.refill(bucket, now);
+            bucket.tokens < self.burst
+        });
+
+        tracked - buckets.len()
+    }
+
+    /// Returns the number of tracked clients, and how many of them are limited.
+    pub fn get_state(&self) -> (usize, usize) {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        let mut limited = 0;
+
+        for bucket in buckets.values_mut() {
+            self.refill(bucket, now);
+
+            if bucket.tokens < 1.0 {
+                limited += 1;
+            }
+        }
+
+        (buckets.len(), limited)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_target;
+    use std::time::Duration;
+
+    fn new_limiter(
+        key: RateLimitKey,
+        burst: u32,
+        max_sources: usize,
+        trusted_hops: usize,
+    ) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfiguration {
+            enabled: true,
+            key,
+            burst,
+            refill_per_second: 1.0,
+            max_sources,
+            trusted_hops,
+            ..Default::default()
+        })
+    }
+
+    fn rewind(limiter: &RateLimiter, key: &str, seconds: u64) {
+        let mut buckets = limiter.lock();
+        let bucket = buckets.get_mut(key).unwrap();
+        bucket.updated_at -= Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn limits_after_burst() {
+        let limiter = new_limiter(RateLimitKey::Source, 3, 10, 1);
+
+        for _ in 0..2 {
+            limiter.record_failure("a");
+        }
+        assert!(!limiter.is_limited("a"));
+
+        limiter.record_failure("a");
+        assert!(limiter.is_limited("a"));
+        assert!(!limiter.is_limited("b"));
+        assert_eq!(limiter.get_state(), (1, 1));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = new_limiter(RateLimitKey::Source, 2, 10, 1);
+
+        limiter.record_failure("a");
+        limiter.record_failure("a");
+        assert!(limiter.is_limited("a"));
+
+        rewind(&limiter, "a", 1);
+        assert!(!limiter.is_limited("a"));
+
+        rewind(&limiter, "a", 60);
+        limiter.record_failure("a");
+        limiter.record_failure("a");
+        assert!(limiter.is_limited("a"));
+    }
+
+    #[test]
+    fn ignores_new_sources_when_full() {
+        let limiter = new_limiter(RateLimitKey::Source, 1, 2, 1);
+
+        limiter.record_failure("a");
+        limiter.record_failure("b");
+        limiter.record_failure("c");
+        assert!(limiter.is_limited("a"));
+        assert!(limiter.is_limited("b"));
+        assert!(!limiter.is_limited("c"));
+        assert_eq!(limiter.get_state(), (2, 2));
+    }
+
+    #[test]
+    fn sweeps_refilled_buckets() {
+        let limiter = new_limiter(RateLimitKey::Source, 1, 2, 1);
+
+        limiter.record_failure("a");
+        limiter.record_failure("b");
+        assert_eq!(limiter.sweep(), 0);
+
+        rewind(&limiter, "a", 5);
+        assert_eq!(limiter.sweep(), 1);
+        assert_eq!(limiter.get_state(), (1, 1));
+
+        limiter.record_failure("c");
+        assert!(limiter.is_limited("c"));
+    }
+
+    #[test]
+    fn reads_forwarded_for_from_the_right() {
+        let headers = HashMap::from([(
+            "x-forwarded-for".to_string(),
+            "1.1.1.1, 2.2.2.2, 3.3.3.3".to_string(),
+        )]);
+        let target = RequestTarget {
+            source_address: "10.0.0.1".to_string(),
+            ..new_target("GET", "app.example.com", "/")
+        };
+
+        let limiter = new_limiter(RateLimitKey::XForwardedFor, 1, 10, 1);
+        assert_eq!(limiter.get_key(&target, &headers).unwrap(), "3.3.3.3");
+
+        let limiter = new_limiter(RateLimitKey::XForwardedFor, 1, 10, 2);
+        assert_eq!(limiter.get_key(&target, &headers).unwrap(), "2.2.2.2");
+
+        let limiter = new_limiter(RateLimitKey::XForwardedFor, 1, 10, 5);
+        assert_eq!(limiter.get_key(&target, &headers).unwrap(), "1.1.1.1");
+
+        assert_eq!(
+            limiter.get_key(&target, &HashMap::new()).unwrap(),
+            "10.0.0.1"
+        );
+
+        let limiter = new_limiter(RateLimitKey::Source, 1, 10, 1);
+        assert_eq!(limiter.get_key(&target, &headers).unwrap(), "10.0.0.1");
+    }
+
+    #[test]
+    fn skips_requests_without_a_client_address() {
+        let headers = HashMap::from([("x-forwarded-for".to_string(), " ".to_string())]);
+        let target = RequestTarget {
+            source_address: String::new(),
+            ..new_target("GET", "app.example.com", "/")
+        };
+
+        for key in [
+            RateLimitKey::Source,
+            RateLimitKey::XForwardedFor,
+            RateLimitKey::CfConnectingIp,
+        ] {
+            let limiter = new_limiter(key, 1, 10, 1);
+            assert!(limiter.get_key(&target, &headers).is_none());
+            assert!(limiter.get_key(&target, &HashMap::new()).is_none());
+        }
+    }
+}
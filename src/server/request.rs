@@ -1,16 +1,156 @@
 use envoy_types::ext_authz::v3::{pb::CheckRequest, CheckRequestExt};
+use envoy_types::pb::envoy::config::core::v3::address::Address;
 use jnt::types::StdResult;
 use serde_json::Value;
 use std::collections::HashMap;
 use tonic::Status;
 
+use crate::config::bootstrap::schema::TokenSource;
+
 pub fn get_headers(req: &CheckRequest) -> super::StatusResult<&HashMap<String, String>> {
     req.get_client_headers()
         .ok_or_else(|| Status::invalid_argument("headers not provided by envoy"))
 }
 
+fn get_cookie_token(headers: &HashMap<String, String>) -> Option<&str> {
+    headers.get("cookie")?.split(';').find_map(|cookie| {
+        let (name, value) = cookie.trim().split_once('=')?;
+        (name == "CF_Authorization").then_some(value)
+    })
+}
+
+fn get_bearer_token(headers: &HashMap<String, String>) -> Option<&str> {
+    let (scheme, token) = headers.get("authorization")?.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+/// Returns the token from the first source, in order, that provides one
+pub fn get_token<'a>(
+    headers: &'a HashMap<String, String>,
+    sources: &[TokenSource],
+) -> Option<(TokenSource, &'a str)> {
+    sources.iter().find_map(|source| {
+        let token = match source {
+            TokenSource::Header => headers.get("cf-access-jwt-assertion").map(|s| s.as_str()),
+            TokenSource::Cookie => get_cookie_token(headers),
+            TokenSource::Bearer => get_bearer_token(headers),
+        };
+
+        token
+            .filter(|token| !token.is_empty())
+            .map(|token| (*source, token))
+    })
+}
+
+/// The HTTP attributes of the original client request. The path is normalised
+/// by `normalise_path`, with any query string or fragment removed.
+pub struct RequestTarget {
+    pub authority: String,
+    pub path: String,
+    pub method: String,
+    pub request_id: String,
+    pub source_address: String,
+}
+
+/// Builds a target for tests, normalising the path as a checked request would
+#[cfg(test)]
+pub fn new_target(method: &str, authority: &str, path: &str) -> RequestTarget {
+    RequestTarget {
+        authority: authority.to_string(),
+        path: normalise_path(path),
+        method: method.to_string(),
+        request_id: String::new(),
+        source_address: String::new(),
+    }
+}
+
+fn get_source_address(req: &CheckRequest) -> String {
+    let address = req
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.source.as_ref())
+        .and_then(|source| source.address.as_ref())
+        .and_then(|address| address.address.as_ref());
+
+    match address {
+        Some(Address::SocketAddress(socket)) => socket.address.to_string(),
+        Some(Address::Pipe(pipe)) => pipe.path.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Resolves dot segments and duplicate slashes, so that a path such as
+/// `/public/../admin` is matched as `/admin`. Encoded dots and slashes,
+/// and backslashes, are decoded first as upstreams may treat them alike.
+pub fn normalise_path(path: &str) -> String {
+    let mut decoded = path.replace('\\', "/");
+
+    for (encoded, value) in [("%2e", "."), ("%2f", "/"), ("%5c", "/")] {
+        while let Some(index) = decoded.to_ascii_lowercase().find(encoded) {
+            decoded.replace_range(index..index + encoded.len(), value);
+        }
+    }
+
+    let mut segments: Vec<&str> = vec![];
+
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalised = format!("/{}", segments.join("/"));
+
+    if !segments.is_empty()
+        && (decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/.."))
+    {
+        normalised.push('/');
+    }
+
+    normalised
+}
+
+/// Whether the path is the prefix itself or lies beneath it. Prefixes match
+/// whole segments only, so `/admin` matches `/admin/users` but not `/administrator`.
+pub fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+impl RequestTarget {
+    pub fn from_check_request(req: &CheckRequest) -> super::StatusResult<Self> {
+        let http = req
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.request.as_ref())
+            .and_then(|request| request.http.as_ref())
+            .ok_or_else(|| Status::invalid_argument("http attributes not provided by envoy"))?;
+
+        Ok(RequestTarget {
+            authority: http.host.to_string(),
+            path: normalise_path(http.path.split(['?', '#']).next().unwrap_or("")),
+            method: http.method.to_string(),
+            request_id: http
+                .headers
+                .get("x-request-id")
+                .unwrap_or(&http.id)
+                .to_string(),
+            source_address: get_source_address(req),
+        })
+    }
+}
+
 type ClaimInteger = u64;
 
+#[derive(Clone)]
 pub struct UserAssertion {
     pub aud: Vec<String>,
     pub email: String,
@@ -22,7 +162,7 @@ pub struct UserAssertion {
     pub nonce: String,
     pub sub: String,
     pub country: String,
-    pub custom: HashMap<String, String>,
+    pub custom: serde_json::map::Map<String, Value>,
 }
 
 fn get_required_claim<'a>(
@@ -69,7 +209,7 @@ fn collect_audiences(object: &serde_json::map::Map<String, Value>) -> StdResult<
     Ok(audiences)
 }
 
-fn force_as_string(value: &Value) -> String {
+pub fn force_as_string(value: &Value) -> String {
     match value.as_str() {
         Some(strval) => strval.to_string(),
         None => value.to_string(),
@@ -78,19 +218,29 @@ fn force_as_string(value: &Value) -> String {
 
 fn get_custom_claims(
     object: &serde_json::map::Map<String, Value>,
-) -> StdResult<HashMap<String, String>> {
+) -> StdResult<serde_json::map::Map<String, Value>> {
     match object.get("custom") {
-        Some(value) => {
-            let mut claims: HashMap<String, String> = HashMap::new();
-            let custom_obj = value.as_object().ok_or("custom claim must be obj")?;
+        Some(value) => Ok(value.as_object().ok_or("custom claim must be obj")?.clone()),
+        None => Ok(serde_json::map::Map::new()),
+    }
+}
 
-            for (custom_claim, custom_val) in custom_obj.into_iter() {
-                claims.insert(custom_claim.to_string(), force_as_string(custom_val));
-            }
+/// Looks up a custom claim by name, descending into nested objects for dotted
+/// names such as `org.team`. Arrays yield each of their elements.
+pub fn get_custom_claim_values(
+    custom: &serde_json::map::Map<String, Value>,
+    claim: &str,
+) -> Vec<String> {
+    let value = custom.get(claim).or_else(|| {
+        let (first, rest) = claim.split_once('.')?;
+        rest.split('.')
+            .try_fold(custom.get(first)?, |value, key| value.get(key))
+    });
 
-            Ok(claims)
-        }
-        None => Ok(HashMap::new()),
+    match value {
+        Some(Value::Array(values)) => values.iter().map(force_as_string).collect(),
+        Some(value) => vec![force_as_string(value)],
+        None => vec![],
     }
 }
 
@@ -112,6 +262,7 @@ impl UserAssertion {
     }
 }
 
+#[derive(Clone)]
 pub struct ServiceAssertion {
     pub aud: Vec<String>,
     pub exp: ClaimInteger,
@@ -134,6 +285,7 @@ impl ServiceAssertion {
     }
 }
 
+#[derive(Clone)]
 pub enum PrincipalAssertion {
     User(UserAssertion),
     Service(ServiceAssertion),
@@ -154,4 +306,144 @@ impl PrincipalAssertion {
 
         Ok(Self::User(UserAssertion::from_claims_object(object)?))
     }
+
+    pub fn get_audiences(&self) -> &[String] {
+        match self {
+            Self::User(assertion) => &assertion.aud,
+            Self::Service(assertion) => &assertion.aud,
+        }
+    }
+
+    pub fn get_issued_at(&self) -> ClaimInteger {
+        match self {
+            Self::User(assertion) => assertion.iat,
+            Self::Service(assertion) => assertion.iat,
+        }
+    }
+
+    pub fn get_expiry(&self) -> ClaimInteger {
+        match self {
+            Self::User(assertion) => assertion.exp,
+            Self::Service(assertion) => assertion.exp,
+        }
+    }
+}
+
+/// Builds a validated user principal for tests
+#[cfg(test)]
+pub fn new_user_assertion() -> PrincipalAssertion {
+    PrincipalAssertion::User(UserAssertion {
+        aud: vec!["app-aud".to_string()],
+        email: "user@example.com".to_string(),
+        exp: 1700003600,
+        iat: 1700000000,
+        nbf: 1700000000,
+        iss: "https://example.cloudflareaccess.com".to_string(),
+        typ: "app".to_string(),
+        nonce: "nonce".to_string(),
+        sub: "user-id".to_string(),
+        country: "AU".to_string(),
+        custom: serde_json::json!({"groups": ["admin"]})
+            .as_object()
+            .unwrap()
+            .clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn gets_token_from_first_source() {
+        let headers = new_headers(&[
+            ("cf-access-jwt-assertion", "header-token"),
+            ("cookie", "theme=dark; CF_Authorization=cookie-token"),
+            ("authorization", "Bearer bearer-token"),
+        ]);
+        let all = [
+            TokenSource::Header,
+            TokenSource::Cookie,
+            TokenSource::Bearer,
+        ];
+
+        assert_eq!(
+            get_token(&headers, &all),
+            Some((TokenSource::Header, "header-token"))
+        );
+        assert_eq!(
+            get_token(&headers, &[TokenSource::Cookie, TokenSource::Header]),
+            Some((TokenSource::Cookie, "cookie-token"))
+        );
+        assert_eq!(
+            get_token(&headers, &[TokenSource::Bearer]),
+            Some((TokenSource::Bearer, "bearer-token"))
+        );
+        assert_eq!(get_token(&new_headers(&[]), &all), None);
+    }
+
+    #[test]
+    fn skips_empty_and_malformed_tokens() {
+        let all = [
+            TokenSource::Header,
+            TokenSource::Cookie,
+            TokenSource::Bearer,
+        ];
+        let headers = new_headers(&[
+            ("cf-access-jwt-assertion", ""),
+            ("cookie", "CF_Authorization="),
+            ("authorization", "bearer  lower-token "),
+        ]);
+
+        assert_eq!(
+            get_token(&headers, &all),
+            Some((TokenSource::Bearer, "lower-token"))
+        );
+
+        let headers = new_headers(&[
+            ("cookie", "cf_authorization=wrong-case"),
+            ("authorization", "Basic dXNlcjpwYXNz"),
+        ]);
+
+        assert_eq!(get_token(&headers, &all), None);
+        assert_eq!(
+            get_token(&new_headers(&[("authorization", "Bearer")]), &all),
+            None
+        );
+    }
+
+    #[test]
+    fn normalises_dot_segments_and_slashes() {
+        assert_eq!(normalise_path(""), "/");
+        assert_eq!(normalise_path("/"), "/");
+        assert_eq!(normalise_path("/public/file.css"), "/public/file.css");
+        assert_eq!(normalise_path("/public/../admin"), "/admin");
+        assert_eq!(normalise_path("/public/./a//b/"), "/public/a/b/");
+        assert_eq!(normalise_path("/../../admin"), "/admin");
+        assert_eq!(normalise_path("/public/a/.."), "/public/");
+    }
+
+    #[test]
+    fn normalises_encoded_dots_and_slashes() {
+        assert_eq!(normalise_path("/public/%2e%2E/admin"), "/admin");
+        assert_eq!(normalise_path("/public/..%2Fadmin"), "/admin");
+        assert_eq!(normalise_path("/public\\..\\admin"), "/admin");
+    }
+
+    #[test]
+    fn matches_path_prefixes_on_segments() {
+        assert!(has_path_prefix("/admin", "/admin"));
+        assert!(has_path_prefix("/admin/users", "/admin"));
+        assert!(has_path_prefix("/admin/users", "/admin/"));
+        assert!(has_path_prefix("/anything", "/"));
+        assert!(!has_path_prefix("/administrator", "/admin"));
+        assert!(!has_path_prefix("/", "/admin"));
+    }
 }
@@ -1,61 +1,676 @@
-use envoy_types::ext_authz::v3::OkHttpResponseBuilder;
+use std::collections::{BTreeMap, HashMap};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use envoy_types::ext_authz::v3::pb::{CheckResponse, HeaderAppendAction, HttpResponse};
+use envoy_types::ext_authz::v3::{
+    CheckResponseExt, DeniedHttpResponseBuilder, OkHttpResponseBuilder,
+};
+use envoy_types::pb::google::protobuf::{value::Kind, ListValue, Struct, Value};
 use jnt::types::EmptyResult;
+use tonic::{Code, Status};
 
+use super::request::force_as_string;
 use super::request::{PrincipalAssertion, ServiceAssertion, UserAssertion};
+use crate::config::bootstrap::schema::{
+    CustomClaimMode, DeniedResponseConfiguration, HeaderConfiguration,
+};
+
+pub fn set_header(
+    builder: &mut OkHttpResponseBuilder,
+    headers: &HeaderConfiguration,
+    field: &str,
+    value: &str,
+) {
+    if let Some(name) = headers.get_header_name(field) {
+        builder.add_header(
+            name,
+            value,
+            Some(HeaderAppendAction::OverwriteIfExistsOrAdd),
+            false,
+        );
+    }
+}
+
+/// Removes identity headers supplied by the client, so upstreams only see
+/// trusted values. Envoy only removes exact names, and applies `headers_to_remove`
+/// after the headers this response sets, so removing every header under the
+/// prefix would also drop the trusted values. Instead:
+/// - headers this response sets overwrite the client's value
+/// - every other fixed identity header is removed, whether or not Envoy
+///   forwarded it in the check request
+/// - custom claim headers the client sent are removed when Envoy forwards them,
+///   so a restricted `allowed_headers` must include the prefix
+pub fn remove_spoofed_headers(
+    response: &mut CheckResponse,
+    client_headers: &HashMap<String, String>,
+    headers: &HeaderConfiguration,
+    remove_token_header: bool,
+) {
+    let Some(HttpResponse::OkResponse(ok_response)) = &mut response.http_response else {
+        return;
+    };
+
+    let trusted: Vec<String> = ok_response
+        .headers
+        .iter()
+        .filter_map(|option| option.header.as_ref())
+        .map(|header| header.key.to_lowercase())
+        .collect();
+
+    let client_identity_headers = client_headers
+        .keys()
+        .filter(|name| headers.is_identity_header(name))
+        .cloned();
+
+    for name in headers
+        .get_identity_header_names()
+        .into_iter()
+        .chain(client_identity_headers)
+    {
+        let name = name.to_lowercase();
+
+        if !trusted.contains(&name) && !ok_response.headers_to_remove.contains(&name) {
+            ok_response.headers_to_remove.push(name);
+        }
+    }
+
+    if remove_token_header {
+        ok_response
+            .headers_to_remove
+            .push("cf-access-jwt-assertion".to_string());
+    }
+}
+
+/// Reduces a claim name to characters that are valid in a header name, so that
+/// names such as `group name` become `group-name`.
+fn sanitise_claim_name(name: &str) -> String {
+    let mut sanitised = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '.' {
+            c
+        } else {
+            '-'
+        };
+
+        if c != '-' || !sanitised.ends_with('-') {
+            sanitised.push(c);
+        }
+    }
+
+    sanitised.trim_matches('-').to_string()
+}
+
+fn flatten_custom_claim(
+    claims: &mut BTreeMap<String, String>,
+    key: String,
+    value: &serde_json::Value,
+    separator: &str,
+) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (nested_key, nested_value) in object {
+                let nested_key = format!("{key}.{nested_key}");
+                flatten_custom_claim(claims, nested_key, nested_value, separator);
+            }
+        }
+        _ if claims.contains_key(&key) => {
+            log::warn!("Dropping custom claim '{key}' colliding with a flattened claim");
+        }
+        serde_json::Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(force_as_string).collect();
+            claims.insert(key, values.join(separator));
+        }
+        value => {
+            claims.insert(key, force_as_string(value));
+        }
+    }
+}
+
+/// Flattens the custom claims into header fields. Nested keys are joined with
+/// dots and arrays with the separator. Keys and names that flatten or sanitise
+/// to the same field are dropped after the first, in claim name order.
+fn flatten_custom_claims(
+    custom: &serde_json::map::Map<String, serde_json::Value>,
+    separator: &str,
+) -> Vec<(String, String)> {
+    let mut claims: BTreeMap<String, String> = BTreeMap::new();
+
+    for (key, value) in custom {
+        flatten_custom_claim(&mut claims, key.to_string(), value, separator);
+    }
+
+    let mut fields: Vec<(String, String)> = vec![];
+
+    for (key, value) in claims {
+        let name = sanitise_claim_name(&key);
+
+        if name.is_empty() {
+            log::warn!("Dropping custom claim '{key}' without a valid header name");
+        } else if fields
+            .iter()
+            .any(|(field, _)| field.eq_ignore_ascii_case(&name))
+        {
+            log::warn!("Dropping custom claim '{key}' colliding with header field '{name}'");
+        } else {
+            fields.push((name, value));
+        }
+    }
 
-fn get_header_name(suffix: &str) -> String {
-    format!("X-Cfzt-Extauthz-{suffix}")
+    fields
 }
 
-fn set_header(builder: &mut OkHttpResponseBuilder, name: &str, value: &str) {
-    builder.add_header(get_header_name(name), value, None, false);
+fn set_custom_claim_headers(
+    builder: &mut OkHttpResponseBuilder,
+    headers: &HeaderConfiguration,
+    custom: &serde_json::map::Map<String, serde_json::Value>,
+) {
+    if custom.is_empty() {
+        return;
+    }
+
+    match headers.custom_claims {
+        CustomClaimMode::Flatten => {
+            for (key, value) in flatten_custom_claims(custom, &headers.custom_claim_separator) {
+                set_header(builder, headers, &format!("Custom-{key}"), &value);
+            }
+        }
+        CustomClaimMode::Base64 => {
+            let json = serde_json::Value::Object(custom.clone()).to_string();
+            set_header(builder, headers, "Custom-Claims", &STANDARD.encode(json));
+        }
+    }
 }
 
 pub trait ResponseMutator {
-    fn mutate_response(&self, builder: &mut OkHttpResponseBuilder) -> EmptyResult;
+    fn mutate_response(
+        &self,
+        builder: &mut OkHttpResponseBuilder,
+        headers: &HeaderConfiguration,
+    ) -> EmptyResult;
 }
 
 impl ResponseMutator for UserAssertion {
-    fn mutate_response(&self, builder: &mut OkHttpResponseBuilder) -> EmptyResult {
-        set_header(builder, "Token-Type", "User");
-        set_header(builder, "Audiences", &self.aud.join(","));
-        set_header(builder, "Email", &self.email);
-        set_header(builder, "Expiry", &self.exp.to_string());
-        set_header(builder, "Issued-At", &self.iat.to_string());
-        set_header(builder, "Not-Before", &self.nbf.to_string());
-        set_header(builder, "Issuer", &self.iss);
-        set_header(builder, "Type", &self.typ);
-        set_header(builder, "Nonce", &self.nonce);
-        set_header(builder, "Subject", &self.sub);
-        set_header(builder, "Country", &self.country);
-
-        for (key, value) in &self.custom {
-            set_header(builder, &format!("Custom-{key}"), value);
-        }
+    fn mutate_response(
+        &self,
+        builder: &mut OkHttpResponseBuilder,
+        headers: &HeaderConfiguration,
+    ) -> EmptyResult {
+        set_header(builder, headers, "Token-Type", "User");
+        set_header(builder, headers, "Audiences", &self.aud.join(","));
+        set_header(builder, headers, "Email", &self.email);
+        set_header(builder, headers, "Expiry", &self.exp.to_string());
+        set_header(builder, headers, "Issued-At", &self.iat.to_string());
+        set_header(builder, headers, "Not-Before", &self.nbf.to_string());
+        set_header(builder, headers, "Issuer", &self.iss);
+        set_header(builder, headers, "Type", &self.typ);
+        set_header(builder, headers, "Nonce", &self.nonce);
+        set_header(builder, headers, "Subject", &self.sub);
+        set_header(builder, headers, "Country", &self.country);
+
+        set_custom_claim_headers(builder, headers, &self.custom);
 
         Ok(())
     }
 }
 
 impl ResponseMutator for ServiceAssertion {
-    fn mutate_response(&self, builder: &mut OkHttpResponseBuilder) -> EmptyResult {
-        set_header(builder, "Token-Type", "User");
-        set_header(builder, "Audiences", &self.aud.join(","));
-        set_header(builder, "Expiry", &self.exp.to_string());
-        set_header(builder, "Issued-At", &self.iat.to_string());
-        set_header(builder, "Issuer", &self.iss);
-        set_header(builder, "Type", &self.typ);
-        set_header(builder, "Common-Name", &self.common_name);
+    fn mutate_response(
+        &self,
+        builder: &mut OkHttpResponseBuilder,
+        headers: &HeaderConfiguration,
+    ) -> EmptyResult {
+        set_header(builder, headers, "Token-Type", "User");
+        set_header(builder, headers, "Audiences", &self.aud.join(","));
+        set_header(builder, headers, "Expiry", &self.exp.to_string());
+        set_header(builder, headers, "Issued-At", &self.iat.to_string());
+        set_header(builder, headers, "Issuer", &self.iss);
+        set_header(builder, headers, "Type", &self.typ);
+        set_header(builder, headers, "Common-Name", &self.common_name);
 
         Ok(())
     }
 }
 
 impl ResponseMutator for PrincipalAssertion {
-    fn mutate_response(&self, builder: &mut OkHttpResponseBuilder) -> EmptyResult {
+    fn mutate_response(
+        &self,
+        builder: &mut OkHttpResponseBuilder,
+        headers: &HeaderConfiguration,
+    ) -> EmptyResult {
         match self {
-            Self::User(assertion) => assertion.mutate_response(builder),
-            Self::Service(assertion) => assertion.mutate_response(builder),
+            Self::User(assertion) => assertion.mutate_response(builder, headers),
+            Self::Service(assertion) => assertion.mutate_response(builder, headers),
+        }
+    }
+}
+
+fn new_string_value(value: &str) -> Value {
+    Value {
+        kind: Some(Kind::StringValue(value.to_string())),
+    }
+}
+
+fn new_list_value(values: &[String]) -> Value {
+    Value {
+        kind: Some(Kind::ListValue(ListValue {
+            values: values.iter().map(|value| new_string_value(value)).collect(),
+        })),
+    }
+}
+
+fn new_json_value(value: &serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(value) => Kind::BoolValue(*value),
+        serde_json::Value::Number(value) => Kind::NumberValue(value.as_f64().unwrap_or_default()),
+        serde_json::Value::String(value) => Kind::StringValue(value.to_string()),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.iter().map(new_json_value).collect(),
+        }),
+        serde_json::Value::Object(object) => Kind::StructValue(Struct {
+            fields: object
+                .iter()
+                .map(|(key, value)| (key.to_string(), new_json_value(value)))
+                .collect(),
+        }),
+    };
+
+    Value { kind: Some(kind) }
+}
+
+/// Describes the principal as dynamic metadata, so that later Envoy filters
+/// and access logs can use it without parsing headers.
+pub fn new_dynamic_metadata(assertion: &PrincipalAssertion) -> Struct {
+    let mut fields: HashMap<String, Value> = HashMap::new();
+
+    match assertion {
+        PrincipalAssertion::User(user) => {
+            fields.insert("type".to_string(), new_string_value("user"));
+            fields.insert("email".to_string(), new_string_value(&user.email));
+            fields.insert("sub".to_string(), new_string_value(&user.sub));
+            fields.insert("audiences".to_string(), new_list_value(&user.aud));
+            fields.insert("country".to_string(), new_string_value(&user.country));
+            fields.insert(
+                "custom".to_string(),
+                new_json_value(&serde_json::Value::Object(user.custom.clone())),
+            );
+        }
+        PrincipalAssertion::Service(service) => {
+            fields.insert("type".to_string(), new_string_value("service"));
+            fields.insert(
+                "common_name".to_string(),
+                new_string_value(&service.common_name),
+            );
+            fields.insert("audiences".to_string(), new_list_value(&service.aud));
+        }
+    }
+
+    Struct { fields }
+}
+
+/// Describes a request allowed by a bypass rule, which carries no principal.
+pub fn new_anonymous_dynamic_metadata() -> Struct {
+    Struct {
+        fields: HashMap::from([("type".to_string(), new_string_value("anonymous"))]),
+    }
+}
+
+/// Converts a failed check into a denied CheckResponse. Only unauthenticated,
+/// permission denied and rate limited failures are converted, anything else
+/// is returned as a gRPC error. The body never carries the failure detail,
+/// which is only logged with the check event.
+pub fn new_denied_response(
+    status: Status,
+    config: &DeniedResponseConfiguration,
+) -> super::StatusResult<CheckResponse> {
+    let (http_status, default_body) = match status.code() {
+        Code::Unauthenticated => (config.unauthenticated_status, "Unauthorized"),
+        Code::PermissionDenied => (config.forbidden_status, "Forbidden"),
+        Code::ResourceExhausted => (config.rate_limited_status, "Too Many Requests"),
+        _ => return Err(status),
+    };
+
+    let mut builder = DeniedHttpResponseBuilder::new();
+    builder.set_http_status(http_status);
+
+    if !config
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+    {
+        builder.add_header("Content-Type", "text/plain", None, false);
+    }
+
+    for (name, value) in &config.headers {
+        builder.add_header(name, value, None, false);
+    }
+
+    builder.set_body(config.body.as_deref().unwrap_or(default_body));
+
+    let mut response = CheckResponse::with_status(status);
+    response.set_http_response(builder);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::new_user_assertion;
+    use envoy_types::ext_authz::v3::pb::{DeniedHttpResponse, HttpStatusCode, OkHttpResponse};
+    use serde_json::json;
+
+    fn flatten(custom: serde_json::Value) -> Vec<(String, String)> {
+        flatten_custom_claims(custom.as_object().unwrap(), ",")
+    }
+
+    #[test]
+    fn sanitises_claim_names() {
+        assert_eq!(sanitise_claim_name("team"), "team");
+        assert_eq!(sanitise_claim_name("group name"), "group-name");
+        assert_eq!(sanitise_claim_name("org.team"), "org.team");
+        assert_eq!(sanitise_claim_name(" a__b: "), "a-b");
+        assert_eq!(sanitise_claim_name("\u{e9}\u{e9}"), "");
+    }
+
+    #[test]
+    fn flattens_nested_claims_and_arrays() {
+        let fields = flatten(json!({
+            "org": {"team": "core", "ids": [1, 2]},
+            "roles": ["admin", "dev"],
+            "active": true
+        }));
+
+        assert_eq!(
+            fields,
+            vec![
+                ("active".to_string(), "true".to_string()),
+                ("org.ids".to_string(), "1,2".to_string()),
+                ("org.team".to_string(), "core".to_string()),
+                ("roles".to_string(), "admin,dev".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_colliding_claims() {
+        let fields = flatten(json!({
+            "org": {"team": "nested"},
+            "org.team": "literal"
+        }));
+        assert_eq!(fields, vec![("org.team".to_string(), "nested".to_string())]);
+
+        let fields = flatten(json!({
+            "Group Name": "a",
+            "group-name": "b",
+            "!!": "c"
+        }));
+        assert_eq!(fields, vec![("Group-Name".to_string(), "a".to_string())]);
+    }
+
+    fn new_ok_response(headers: &HeaderConfiguration, fields: &[(&str, &str)]) -> CheckResponse {
+        let mut builder = OkHttpResponseBuilder::new();
+
+        for (field, value) in fields {
+            set_header(&mut builder, headers, field, value);
+        }
+
+        let mut response = CheckResponse::with_status(Status::ok("token validated"));
+        response.set_http_response(builder);
+        response
+    }
+
+    fn new_client_headers(names: &[&str]) -> HashMap<String, String> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), "spoofed".to_string()))
+            .collect()
+    }
+
+    fn get_ok_response(response: &CheckResponse) -> &OkHttpResponse {
+        match &response.http_response {
+            Some(HttpResponse::OkResponse(ok_response)) => ok_response,
+            _ => panic!("not an ok response"),
+        }
+    }
+
+    fn get_set_headers(response: &CheckResponse) -> Vec<String> {
+        get_ok_response(response)
+            .headers
+            .iter()
+            .filter_map(|option| option.header.as_ref())
+            .map(|header| header.key.to_lowercase())
+            .collect()
+    }
+
+    #[test]
+    fn removes_client_headers_under_the_prefix() {
+        let headers = HeaderConfiguration::default();
+        let mut response = new_ok_response(&headers, &[("Email", "user@example.com")]);
+        let client_headers = new_client_headers(&["x-cfzt-extauthz-admin", "accept"]);
+
+        remove_spoofed_headers(&mut response, &client_headers, &headers, false);
+        let removed = &get_ok_response(&response).headers_to_remove;
+
+        assert!(removed.contains(&"x-cfzt-extauthz-admin".to_string()));
+        assert!(removed.contains(&"x-cfzt-extauthz-groups".to_string()));
+        assert!(!removed.contains(&"accept".to_string()));
+        assert!(!removed.contains(&"cf-access-jwt-assertion".to_string()));
+    }
+
+    #[test]
+    fn overwrites_client_headers_that_are_also_set() {
+        let headers = HeaderConfiguration::default();
+        let mut response = new_ok_response(&headers, &[("Email", "user@example.com")]);
+        let client_headers = new_client_headers(&["x-cfzt-extauthz-email"]);
+
+        remove_spoofed_headers(&mut response, &client_headers, &headers, true);
+        let removed = &get_ok_response(&response).headers_to_remove;
+
+        assert!(!removed.contains(&"x-cfzt-extauthz-email".to_string()));
+        assert!(get_set_headers(&response).contains(&"x-cfzt-extauthz-email".to_string()));
+        assert!(removed.contains(&"cf-access-jwt-assertion".to_string()));
+    }
+
+    #[test]
+    fn matches_the_prefix_case_insensitively() {
+        let headers = HeaderConfiguration::default();
+        let mut response = new_ok_response(&headers, &[("Email", "user@example.com")]);
+        let client_headers =
+            new_client_headers(&["X-CFZT-EXTAUTHZ-Admin", "X-Cfzt-Extauthz-Email"]);
+
+        remove_spoofed_headers(&mut response, &client_headers, &headers, false);
+        let removed = &get_ok_response(&response).headers_to_remove;
+
+        assert!(removed.contains(&"x-cfzt-extauthz-admin".to_string()));
+        assert!(!removed.contains(&"x-cfzt-extauthz-email".to_string()));
+    }
+
+    #[test]
+    fn removes_client_headers_under_a_custom_prefix() {
+        let headers = HeaderConfiguration {
+            prefix: "X-Auth-".to_string(),
+            ..HeaderConfiguration::default()
+        };
+        let mut response = new_ok_response(&headers, &[("Email", "user@example.com")]);
+        let client_headers = new_client_headers(&[
+            "x-auth-email",
+            "x-auth-custom-team",
+            "x-cfzt-extauthz-admin",
+        ]);
+
+        remove_spoofed_headers(&mut response, &client_headers, &headers, false);
+        let removed = &get_ok_response(&response).headers_to_remove;
+
+        assert!(removed.contains(&"x-auth-custom-team".to_string()));
+        assert!(removed.contains(&"x-auth-subject".to_string()));
+        assert!(!removed.contains(&"x-auth-email".to_string()));
+        assert!(!removed.contains(&"x-cfzt-extauthz-admin".to_string()));
+        assert_eq!(get_set_headers(&response), vec!["x-auth-email"]);
+    }
+
+    fn get_denied_response(response: &CheckResponse) -> &DeniedHttpResponse {
+        match &response.http_response {
+            Some(HttpResponse::DeniedResponse(denied_response)) => denied_response,
+            _ => panic!("not a denied response"),
         }
     }
+
+    fn get_denied_headers(response: &CheckResponse) -> Vec<(String, String)> {
+        get_denied_response(response)
+            .headers
+            .iter()
+            .filter_map(|option| option.header.as_ref())
+            .map(|header| (header.key.to_lowercase(), header.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn maps_denials_to_http_statuses() {
+        let config = DeniedResponseConfiguration::default();
+        let cases = [
+            (Status::unauthenticated(""), 401, "Unauthorized"),
+            (Status::permission_denied(""), 403, "Forbidden"),
+            (Status::resource_exhausted(""), 429, "Too Many Requests"),
+        ];
+
+        for (status, code, body) in cases {
+            let response = new_denied_response(status, &config).unwrap();
+            let denied_response = get_denied_response(&response);
+
+            assert_eq!(denied_response.status.as_ref().unwrap().code, code);
+            assert_eq!(denied_response.body, body);
+        }
+
+        let status = new_denied_response(Status::internal("boom"), &config).unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+    }
+
+    #[test]
+    fn uses_configured_statuses_and_body() {
+        let config = DeniedResponseConfiguration {
+            unauthenticated_status: HttpStatusCode::Found,
+            forbidden_status: HttpStatusCode::NotFound,
+            rate_limited_status: HttpStatusCode::ServiceUnavailable,
+            body: Some("Go away".to_string()),
+            headers: vec![],
+        };
+        let cases = [
+            (Status::unauthenticated(""), 302),
+            (Status::permission_denied(""), 404),
+            (Status::resource_exhausted(""), 503),
+        ];
+
+        for (status, code) in cases {
+            let response = new_denied_response(status, &config).unwrap();
+            let denied_response = get_denied_response(&response);
+
+            assert_eq!(denied_response.status.as_ref().unwrap().code, code);
+            assert_eq!(denied_response.body, "Go away");
+        }
+    }
+
+    #[test]
+    fn defaults_the_content_type() {
+        let config = DeniedResponseConfiguration {
+            headers: vec![("Location".to_string(), "/login".to_string())],
+            ..DeniedResponseConfiguration::default()
+        };
+        let response = new_denied_response(Status::unauthenticated(""), &config).unwrap();
+
+        assert_eq!(
+            get_denied_headers(&response),
+            vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("location".to_string(), "/login".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_a_configured_content_type() {
+        let config = DeniedResponseConfiguration {
+            body: Some("{}".to_string()),
+            headers: vec![("content-TYPE".to_string(), "application/json".to_string())],
+            ..DeniedResponseConfiguration::default()
+        };
+        let response = new_denied_response(Status::permission_denied(""), &config).unwrap();
+
+        assert_eq!(
+            get_denied_headers(&response),
+            vec![("content-type".to_string(), "application/json".to_string())]
+        );
+    }
+
+    fn get_string_field<'a>(metadata: &'a Struct, name: &str) -> &'a str {
+        match &metadata.fields[name].kind {
+            Some(Kind::StringValue(value)) => value,
+            _ => panic!("{name} is not a string"),
+        }
+    }
+
+    fn get_field_names(metadata: &Struct) -> Vec<&str> {
+        let mut names: Vec<&str> = metadata.fields.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn describes_users_in_dynamic_metadata() {
+        let metadata = new_dynamic_metadata(&new_user_assertion());
+
+        // Fields sit at the top level, so Envoy exposes them directly under
+        // the ext_authz filter namespace
+        assert_eq!(
+            get_field_names(&metadata),
+            vec!["audiences", "country", "custom", "email", "sub", "type"]
+        );
+        assert_eq!(get_string_field(&metadata, "type"), "user");
+        assert_eq!(get_string_field(&metadata, "email"), "user@example.com");
+        assert_eq!(get_string_field(&metadata, "sub"), "user-id");
+        assert_eq!(get_string_field(&metadata, "country"), "AU");
+        assert_eq!(
+            metadata.fields["audiences"],
+            new_list_value(&["app-aud".to_string()])
+        );
+        assert_eq!(
+            metadata.fields["custom"],
+            new_json_value(&json!({"groups": ["admin"]}))
+        );
+    }
+
+    #[test]
+    fn describes_services_in_dynamic_metadata() {
+        let metadata = new_dynamic_metadata(&PrincipalAssertion::Service(ServiceAssertion {
+            aud: vec!["app-aud".to_string()],
+            exp: 0,
+            iat: 0,
+            iss: "https://example.cloudflareaccess.com".to_string(),
+            typ: "app".to_string(),
+            common_name: "client-id.access".to_string(),
+        }));
+
+        assert_eq!(
+            get_field_names(&metadata),
+            vec!["audiences", "common_name", "type"]
+        );
+        assert_eq!(get_string_field(&metadata, "type"), "service");
+        assert_eq!(
+            get_string_field(&metadata, "common_name"),
+            "client-id.access"
+        );
+        assert_eq!(
+            metadata.fields["audiences"],
+            new_list_value(&["app-aud".to_string()])
+        );
+    }
+
+    #[test]
+    fn describes_bypassed_requests_as_anonymous() {
+        let metadata = new_anonymous_dynamic_metadata();
+
+        assert_eq!(get_field_names(&metadata), vec!["type"]);
+        assert_eq!(get_string_field(&metadata, "type"), "anonymous");
+    }
 }
@@ -1,9 +1,48 @@
-use crate::config::bootstrap::schema::{ValidatorConfiguration, StaticTeamValidatorConfiguration, CommonValidatorConfiguration};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use super::health::KeyHealthMonitor;
+use crate::config::bootstrap::schema::{
+    CommonValidatorConfiguration, StaticTeamValidatorConfiguration, ValidatorConfiguration,
+};
 use jnt::types::StdResult;
-use rust_cfzt_validator::{TeamValidator, Validator, api::TeamKeys};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rust_cfzt_validator::{api::TeamKeys, DecodedToken, TeamValidator, Validator};
+use tokio::sync::watch;
+
+pub fn get_team_issuer(team_name: &str) -> String {
+    format!("https://{team_name}.cloudflareaccess.com")
+}
+
+/// Reads the `iss` claim of a token without verifying its signature,
+/// so that the matching team key set can be selected for validation.
+pub fn get_unverified_issuer(token: &str) -> StdResult<String> {
+    let mut constraints = Validation::new(Algorithm::RS256);
+    constraints.insecure_disable_signature_validation();
+    constraints.validate_exp = false;
+    constraints.validate_aud = false;
+    constraints.required_spec_claims.clear();
+
+    let data = jsonwebtoken::decode::<serde_json::Value>(
+        token,
+        &DecodingKey::from_secret(&[]),
+        &constraints,
+    )?;
+
+    Ok(data
+        .claims
+        .get("iss")
+        .ok_or("iss claim missing")?
+        .as_str()
+        .ok_or("iss claim should be str")?
+        .to_string())
+}
 
-fn new_single_team_configuration(static_config: &StaticTeamValidatorConfiguration, common_config: &CommonValidatorConfiguration) -> StdResult<TeamValidator> {
-    let mut builder = ureq::Agent::config_builder();
+pub fn new_agent(common_config: &CommonValidatorConfiguration, timeout: Option<Duration>) -> ureq::Agent {
+    let mut builder = ureq::Agent::config_builder().timeout_global(timeout);
 
     if common_config.proxy_discovery {
         builder = builder.proxy(
@@ -11,21 +50,368 @@ fn new_single_team_configuration(static_config: &StaticTeamValidatorConfiguratio
         );
     }
 
-    let agent = ureq::Agent::new_with_config(builder.build());
+    ureq::Agent::new_with_config(builder.build())
+}
+
+/// A team's validator along with the ids of the keys it holds
+struct TeamKeySet {
+    validator: TeamValidator,
+    key_ids: HashSet<String>,
+}
+
+impl TeamKeySet {
+    fn new(team_keys: TeamKeys, agent: ureq::Agent) -> Self {
+        TeamKeySet {
+            key_ids: team_keys.keys.keys().cloned().collect(),
+            validator: TeamValidator::from_team_keys(team_keys, agent),
+        }
+    }
+}
+
+/// Why a token could not be validated against its team's keys
+#[derive(Debug)]
+pub enum KeyValidationError {
+    /// The token was signed with a key id that is not in the team's key set
+    UnknownKey(String),
+    Invalid(String),
+}
 
-    match &static_config.static_keys {
-        Some(team_keys) => Ok(TeamValidator::from_team_keys(
-            TeamKeys::from_str(&static_config.team_name, &team_keys)?,
-            agent,
-        )),
-        None => TeamValidator::from_team_name(&static_config.team_name, agent),
+impl fmt::Display for KeyValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownKey(kid) => write!(f, "kid '{kid}' is not in the team's key set"),
+            Self::Invalid(message) => write!(f, "{message}"),
+        }
     }
 }
 
-pub fn new_validator(configuration: &ValidatorConfiguration) -> StdResult<Box<dyn Validator>> {
+impl Error for KeyValidationError {}
+
+/// The outcome of an on-demand refresh, shared with the requests waiting on it
+type RefreshResult = Option<Result<bool, String>>;
+
+/// The on-demand refresh state of a team. A refresh that is running is
+/// shared through its channel, so the lock is never held while fetching.
+#[derive(Default)]
+struct TeamRefresh {
+    started_at: Option<Instant>,
+    refreshed_at: Option<Instant>,
+    in_flight: Option<watch::Receiver<RefreshResult>>,
+}
+
+/// Fetches a team's published keys through the team's agent
+type KeyFetcher = dyn Fn(&str, &ureq::Agent) -> StdResult<TeamKeys> + Send + Sync;
+
+/// Holds a separate key set for every configured team. Each team is
+/// synchronised independently, and teams with static keys are never refreshed.
+pub struct TeamSetValidator {
+    teams: RwLock<HashMap<String, TeamKeySet>>,
+    agents: HashMap<String, ureq::Agent>,
+    fetch_keys: Box<KeyFetcher>,
+    refresh_interval: Duration,
+    refreshes: Mutex<HashMap<String, TeamRefresh>>,
+    health: Option<Arc<KeyHealthMonitor>>,
+}
+
+impl TeamSetValidator {
+    fn new(
+        team_configs: &[StaticTeamValidatorConfiguration],
+        common_config: &CommonValidatorConfiguration,
+        refresh_interval: Duration,
+        timeout: Duration,
+        fetch_keys: Box<KeyFetcher>,
+    ) -> StdResult<Self> {
+        let mut teams = HashMap::new();
+        let mut agents = HashMap::new();
+
+        for team_config in team_configs {
+            let agent = new_agent(common_config, Some(timeout));
+            let team_keys = match &team_config.static_keys {
+                Some(static_keys) => TeamKeys::from_str(&team_config.team_name, static_keys)?,
+                None => {
+                    agents.insert(team_config.team_name.to_string(), agent.clone());
+                    fetch_keys(&team_config.team_name, &agent)?
+                }
+            };
+
+            teams.insert(team_config.team_name.to_string(), TeamKeySet::new(team_keys, agent));
+        }
+
+        Ok(TeamSetValidator {
+            teams: RwLock::new(teams),
+            agents,
+            fetch_keys,
+            refresh_interval,
+            refreshes: Mutex::new(HashMap::new()),
+            health: None,
+        })
+    }
+
+    /// Reports on-demand refreshes to the health monitor, which also tracks
+    /// the scheduled synchronisation
+    pub fn with_health_monitor(mut self, health: Arc<KeyHealthMonitor>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    pub fn validate_token(
+        &self,
+        token: &str,
+        team_name: &str,
+        constraints: &mut Validation,
+    ) -> Result<DecodedToken, KeyValidationError> {
+        let teams = self.teams.read().unwrap_or_else(PoisonError::into_inner);
+        let team = teams
+            .get(team_name)
+            .ok_or_else(|| KeyValidationError::Invalid(format!("team name '{team_name}' not found")))?;
+
+        let kid = jsonwebtoken::decode_header(token)
+            .map_err(|e| KeyValidationError::Invalid(format!("failed to decode jwt header: {e}")))?
+            .kid
+            .ok_or_else(|| KeyValidationError::Invalid("no kid in jwt header".to_string()))?;
+
+        if !team.key_ids.contains(&kid) {
+            return Err(KeyValidationError::UnknownKey(kid));
+        }
+
+        team.validator
+            .validate_token(token, team_name, constraints)
+            .map_err(|e| KeyValidationError::Invalid(e.to_string()))
+    }
+
+    /// Fetches a team's published keys, replacing the held key set whenever it
+    /// differs. Unlike the library's own sync, a key published next to the
+    /// current keys is loaded straight away rather than once the old keys retire.
+    pub fn sync_team(&self, team_name: &str) -> StdResult<bool> {
+        let agent = self
+            .agents
+            .get(team_name)
+            .ok_or(format!("team {team_name} does not refresh its keys"))?;
+        let team_keys = (self.fetch_keys)(team_name, agent)?;
+        let key_ids: HashSet<String> = team_keys.keys.keys().cloned().collect();
+        let mut teams = self.teams.write().unwrap_or_else(PoisonError::into_inner);
+
+        if teams.get(team_name).is_some_and(|team| team.key_ids == key_ids) {
+            return Ok(false);
+        }
+
+        teams.insert(team_name.to_string(), TeamKeySet::new(team_keys, agent.clone()));
+        Ok(true)
+    }
+
+    /// The teams whose keys are fetched from Cloudflare, rather than held statically
+    pub fn get_refreshed_team_names(&self) -> Vec<String> {
+        let mut team_names: Vec<String> = self.agents.keys().cloned().collect();
+        team_names.sort();
+        team_names
+    }
+
+    /// Synchronises a team's keys off the async runtime.
+    pub async fn sync_team_blocking(self: Arc<Self>, team_name: String) -> StdResult<bool> {
+        tokio::task::spawn_blocking(move || self.sync_team(&team_name).map_err(|e| e.to_string()))
+            .await?
+            .map_err(|e| e.into())
+    }
+
+    /// Refreshes a team's keys after a token checked at `checked_at` named an
+    /// unknown key id. Concurrent callers share a single refresh, and a team is
+    /// refreshed at most once per refresh interval. Returns None if no refresh
+    /// took place, otherwise whether the keys rotated.
+    pub async fn refresh_team(self: Arc<Self>, team_name: &str, checked_at: Instant) -> Option<StdResult<bool>> {
+        if self.refresh_interval.is_zero() || !self.agents.contains_key(team_name) {
+            return None;
+        }
+
+        let mut receiver = {
+            let mut refreshes = self.refreshes.lock().unwrap_or_else(PoisonError::into_inner);
+            let refresh = refreshes.entry(team_name.to_string()).or_default();
+
+            match &refresh.in_flight {
+                Some(receiver) => receiver.clone(),
+                // Another request's refresh completed after this token was checked
+                None if refresh.refreshed_at.is_some_and(|refreshed_at| refreshed_at >= checked_at) => {
+                    return Some(Ok(false));
+                }
+                None if refresh.started_at.is_some_and(|started_at| started_at.elapsed() < self.refresh_interval) => {
+                    return None;
+                }
+                None => {
+                    log::info!("Refreshing keys for team {team_name} after unknown key id");
+                    let (sender, receiver) = watch::channel(None);
+                    refresh.started_at = Some(Instant::now());
+                    refresh.in_flight = Some(receiver.clone());
+                    self.clone().start_refresh(team_name.to_string(), sender);
+                    receiver
+                }
+            }
+        };
+
+        let result = receiver.wait_for(|result| result.is_some()).await.ok()?.clone()?;
+        Some(result.map_err(|e| e.into()))
+    }
+
+    /// Fetches a team's keys off the async runtime. The refresh state is updated
+    /// once the fetch completes, even if every waiting request was cancelled.
+    fn start_refresh(self: Arc<Self>, team_name: String, sender: watch::Sender<RefreshResult>) {
+        tokio::task::spawn_blocking(move || {
+            let result = self.sync_team(&team_name).map_err(|e| e.to_string());
+
+            if let Err(e) = &result {
+                log::error!("Failed to refresh keys for team {team_name}: {e}");
+            }
+
+            if let Some(health) = &self.health {
+                health.record_sync(&team_name, &result);
+            }
+
+            let mut refreshes = self.refreshes.lock().unwrap_or_else(PoisonError::into_inner);
+            let refresh = refreshes.entry(team_name).or_default();
+            refresh.in_flight = None;
+
+            if result.is_ok() {
+                refresh.refreshed_at = Some(Instant::now());
+            }
+
+            sender.send_replace(Some(result));
+        });
+    }
+}
+
+pub fn new_validator(configuration: &ValidatorConfiguration, refresh_interval: Duration, timeout: Duration) -> StdResult<TeamSetValidator> {
     match configuration {
         ValidatorConfiguration::Team(static_config, common_config) => {
-            Ok(Box::new(new_single_team_configuration(static_config, common_config)?))
+            TeamSetValidator::new(std::slice::from_ref(static_config), common_config, refresh_interval, timeout, Box::new(TeamKeys::from_team_name))
+        }
+        ValidatorConfiguration::MultiTeam(multi_config, common_config) => {
+            TeamSetValidator::new(&multi_config.teams, common_config, refresh_interval, timeout, Box::new(TeamKeys::from_team_name))
         }
     }
 }
+
+/// Builds a validator for tests, whose teams fetch their keys through `fetch_keys`
+#[cfg(test)]
+pub fn new_fetching_validator(
+    team_names: &[&str],
+    refresh_interval: Duration,
+    fetch_keys: impl Fn(&str) -> StdResult<TeamKeys> + Send + Sync + 'static,
+) -> TeamSetValidator {
+    let team_configs: Vec<StaticTeamValidatorConfiguration> = team_names
+        .iter()
+        .map(|team_name| StaticTeamValidatorConfiguration { team_name: team_name.to_string(), static_keys: None })
+        .collect();
+
+    TeamSetValidator::new(
+        &team_configs,
+        &CommonValidatorConfiguration { proxy_discovery: false },
+        refresh_interval,
+        Duration::from_secs(1),
+        Box::new(move |team_name, _| fetch_keys(team_name)),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::new_team_keys_json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Builds a validator for team "example" that counts its fetches. The
+    /// first fetch publishes "key-1" and each later one is answered by `refetch`.
+    fn new_counting_validator(
+        refresh_interval: Duration,
+        refetch: impl Fn() -> StdResult<TeamKeys> + Send + Sync + 'static,
+    ) -> (Arc<TeamSetValidator>, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counted = fetches.clone();
+        let validator = new_fetching_validator(&["example"], refresh_interval, move |team_name| {
+            match counted.fetch_add(1, Ordering::SeqCst) {
+                0 => TeamKeys::from_str(team_name, &new_team_keys_json(&["key-1"])),
+                _ => refetch(),
+            }
+        });
+
+        (Arc::new(validator), fetches)
+    }
+
+    fn new_rotated_keys() -> StdResult<TeamKeys> {
+        // Holds the refresh open long enough for concurrent callers to join it
+        std::thread::sleep(Duration::from_millis(100));
+        TeamKeys::from_str("example", &new_team_keys_json(&["key-1", "key-2"]))
+    }
+
+    #[tokio::test]
+    async fn shares_a_single_refresh_between_concurrent_callers() {
+        let (validator, fetches) = new_counting_validator(Duration::from_secs(60), new_rotated_keys);
+        let checked_at = Instant::now();
+
+        let results = tokio::join!(
+            validator.clone().refresh_team("example", checked_at),
+            validator.clone().refresh_team("example", checked_at),
+        );
+
+        assert!(matches!(results.0, Some(Ok(true))));
+        assert!(matches!(results.1, Some(Ok(true))));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshes_at_most_once_per_interval() {
+        let (validator, fetches) = new_counting_validator(Duration::from_secs(60), new_rotated_keys);
+
+        let result = validator.clone().refresh_team("example", Instant::now()).await;
+        assert!(matches!(result, Some(Ok(true))));
+
+        let result = validator.clone().refresh_team("example", Instant::now()).await;
+        assert!(result.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn skips_the_refresh_for_tokens_checked_before_the_last_one() {
+        let (validator, fetches) = new_counting_validator(Duration::from_secs(60), new_rotated_keys);
+        let checked_at = Instant::now();
+
+        let result = validator.clone().refresh_team("example", Instant::now()).await;
+        assert!(matches!(result, Some(Ok(true))));
+
+        // The token was checked against the old keys, it is worth another check
+        let result = validator.clone().refresh_team("example", checked_at).await;
+        assert!(matches!(result, Some(Ok(false))));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn returns_failed_refreshes() {
+        let (validator, fetches) = new_counting_validator(Duration::from_secs(60), || Err("unavailable".into()));
+
+        let result = validator.clone().refresh_team("example", Instant::now()).await;
+        assert_eq!(result.unwrap().unwrap_err().to_string(), "unavailable");
+
+        // A failed refresh still counts towards the interval
+        let result = validator.clone().refresh_team("example", Instant::now()).await;
+        assert!(result.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn skips_the_refresh_when_disabled_or_static() {
+        let (validator, fetches) = new_counting_validator(Duration::ZERO, new_rotated_keys);
+        assert!(validator.clone().refresh_team("example", Instant::now()).await.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        let (validator, fetches) = new_counting_validator(Duration::from_secs(60), new_rotated_keys);
+        assert!(validator.clone().refresh_team("other", Instant::now()).await.is_none());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reports_whether_a_sync_rotated_the_keys() {
+        let (validator, _) = new_counting_validator(Duration::ZERO, || TeamKeys::from_str("example", &new_team_keys_json(&["key-1"])));
+        assert!(!validator.sync_team("example").unwrap());
+
+        let (validator, _) = new_counting_validator(Duration::ZERO, new_rotated_keys);
+        assert!(validator.sync_team("example").unwrap());
+        assert!(validator.sync_team("other").is_err());
+    }
+}
@@ -1,21 +1,29 @@
 use jnt::sockets::Listener;
 use jnt::types::{EmptyResult, StdResult, UnixListener};
+use std::future::Future;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tokio::net::{TcpListener as TokioTcpListener, UnixListener as TokioUnixListener};
 use tokio_stream::wrappers::TcpListenerStream;
 use tonic::transport::server::Router;
 
+use crate::tls::{accept_tls, ReloadableTlsConfig};
+
 #[cfg(unix)]
 use tokio_stream::wrappers::UnixListenerStream;
 
 #[cfg(not(unix))]
 type UnixListenerStream = ();
 
+#[cfg(unix)]
+fn bind_unix_listener(listener: UnixListener) -> StdResult<TokioUnixListener> {
+    listener.set_nonblocking(true)?;
+    Ok(TokioUnixListener::from_std(listener)?)
+}
+
 #[cfg(unix)]
 fn bind_unix_socket(listener: UnixListener) -> StdResult<UnixListenerStream> {
-    Ok(UnixListenerStream::new(TokioUnixListener::from_std(
-        listener,
-    )?))
+    Ok(UnixListenerStream::new(bind_unix_listener(listener)?))
 }
 
 #[cfg(not(unix))]
@@ -23,10 +31,13 @@ fn bind_unix_socket() -> StdResult<UnixListenerStream> {
     jnt::opaque_err!("unsupported platform")
 }
 
+fn bind_tcp_listener(listener: TcpListener) -> StdResult<TokioTcpListener> {
+    listener.set_nonblocking(true)?;
+    Ok(TokioTcpListener::from_std(listener)?)
+}
+
 fn bind_tcp_socket(listener: TcpListener) -> StdResult<TcpListenerStream> {
-    Ok(TcpListenerStream::new(TokioTcpListener::from_std(
-        listener,
-    )?))
+    Ok(TcpListenerStream::new(bind_tcp_listener(listener)?))
 }
 
 fn handle_result(result: Result<(), tonic::transport::Error>) -> EmptyResult {
@@ -36,13 +47,55 @@ fn handle_result(result: Result<(), tonic::transport::Error>) -> EmptyResult {
     }
 }
 
-pub async fn run_server(router: Router, listener: Listener) -> EmptyResult {
+/// Serves until the shutdown signal resolves, then stops accepting connections
+/// and waits for in-flight requests to complete.
+pub async fn run_server(
+    router: Router,
+    listener: Listener,
+    tls: Option<Arc<ReloadableTlsConfig>>,
+    signal: impl Future<Output = ()>,
+) -> EmptyResult {
+    match (listener, tls) {
+        (Listener::Unix(socket), None) => handle_result(
+            router
+                .serve_with_incoming_shutdown(bind_unix_socket(socket)?, signal)
+                .await,
+        ),
+        (Listener::Unix(socket), Some(tls)) => handle_result(
+            router
+                .serve_with_incoming_shutdown(accept_tls(bind_unix_socket(socket)?, tls), signal)
+                .await,
+        ),
+        (Listener::Tcp(socket), None) => handle_result(
+            router
+                .serve_with_incoming_shutdown(bind_tcp_socket(socket)?, signal)
+                .await,
+        ),
+        (Listener::Tcp(socket), Some(tls)) => handle_result(
+            router
+                .serve_with_incoming_shutdown(accept_tls(bind_tcp_socket(socket)?, tls), signal)
+                .await,
+        ),
+    }
+}
+
+pub async fn run_http_server(
+    router: axum::Router,
+    listener: Listener,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> EmptyResult {
     match listener {
         Listener::Unix(socket) => {
-            handle_result(router.serve_with_incoming(bind_unix_socket(socket)?).await)
+            let listener = bind_unix_listener(socket)?;
+            Ok(axum::serve(listener, router)
+                .with_graceful_shutdown(signal)
+                .await?)
         }
         Listener::Tcp(socket) => {
-            handle_result(router.serve_with_incoming(bind_tcp_socket(socket)?).await)
+            let listener = bind_tcp_listener(socket)?;
+            Ok(axum::serve(listener, router)
+                .with_graceful_shutdown(signal)
+                .await?)
         }
     }
 }
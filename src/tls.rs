@@ -0,0 +1,349 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use jnt::types::{BoolResult, StdResult};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::config::bootstrap::schema::TlsConfiguration;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read_certificates(path: &str) -> StdResult<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate file {path}: {e}"))?;
+
+    if certificates.is_empty() {
+        return Err(format!("certificate file {path} contains no certificates").into());
+    }
+
+    Ok(certificates)
+}
+
+fn read_private_key(path: &str) -> StdResult<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))
+        .map_err(|e| format!("invalid key file {path}: {e}"))?
+        .ok_or_else(|| format!("key file {path} contains no private key").into())
+}
+
+fn new_server_config(configuration: &TlsConfiguration) -> StdResult<ServerConfig> {
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &configuration.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+
+            for certificate in read_certificates(client_ca_file)? {
+                roots.add(certificate)?;
+            }
+
+            builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(
+        read_certificates(&configuration.cert_file)?,
+        read_private_key(&configuration.key_file)?,
+    )?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(config)
+}
+
+/// Holds the listener's TLS configuration, rebuilding it when the
+/// certificate, key or client CA files change on disk.
+pub struct ReloadableTlsConfig {
+    configuration: TlsConfiguration,
+    server_config: RwLock<Arc<ServerConfig>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadableTlsConfig {
+    pub fn new(configuration: TlsConfiguration) -> StdResult<Self> {
+        Ok(ReloadableTlsConfig {
+            server_config: RwLock::new(Arc::new(new_server_config(&configuration)?)),
+            modified: Mutex::new(Self::get_modified(&configuration)),
+            configuration,
+        })
+    }
+
+    fn get_modified(configuration: &TlsConfiguration) -> Vec<Option<SystemTime>> {
+        [
+            Some(&configuration.cert_file),
+            Some(&configuration.key_file),
+            configuration.client_ca_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+    }
+
+    pub fn get_reload_schedule(&self) -> &str {
+        &self.configuration.reload_schedule
+    }
+
+    /// Reloads the configuration if any of its files changed.
+    /// Returns a bool signalling if the configuration was replaced.
+    pub fn refresh(&self) -> BoolResult {
+        let latest = Self::get_modified(&self.configuration);
+        let mut modified = self.modified.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if latest == *modified {
+            return Ok(false);
+        }
+
+        let server_config = Arc::new(new_server_config(&self.configuration)?);
+
+        *self
+            .server_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = server_config;
+
+        *modified = latest;
+        Ok(true)
+    }
+
+    fn get_acceptor(&self) -> TlsAcceptor {
+        let server_config = self
+            .server_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        TlsAcceptor::from(server_config)
+    }
+}
+
+/// Performs the TLS handshake for every accepted connection, each on its own
+/// task so that slow clients do not hold up the accept loop.
+pub fn accept_tls<S, IO>(
+    mut incoming: S,
+    tls: Arc<ReloadableTlsConfig>,
+) -> ReceiverStream<std::io::Result<TlsStream<IO>>>
+where
+    S: Stream<Item = std::io::Result<IO>> + Unpin + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let connection = tokio::select! {
+                _ = sender.closed() => break,
+                connection = incoming.next() => connection,
+            };
+
+            let stream = match connection {
+                Some(Ok(stream)) => stream,
+                Some(Err(e)) => {
+                    log::warn!("Failed to accept connection: {e}");
+                    continue;
+                }
+                None => break,
+            };
+
+            let acceptor = tls.get_acceptor();
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        sender.send(Ok(tls_stream)).await.ok();
+                    }
+                    Ok(Err(e)) => log::debug!("TLS handshake failed: {e}"),
+                    Err(_) => log::debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::new_temp_dir;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+    use std::path::Path;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    struct Issuer {
+        certificate: Certificate,
+        key: KeyPair,
+    }
+
+    fn new_issuer() -> Issuer {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+
+        Issuer {
+            certificate: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    /// Issues a certificate for localhost, returning it with its key
+    fn issue(issuer: &Issuer) -> (Certificate, KeyPair) {
+        let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let certificate = params
+            .signed_by(&key, &issuer.certificate, &issuer.key)
+            .unwrap();
+
+        (certificate, key)
+    }
+
+    /// Writes a file, moving its modification time forward so that the
+    /// change is seen however coarse the filesystem's timestamps are
+    fn write(path: &Path, contents: &str) {
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_or(SystemTime::now(), |modified| {
+                modified + Duration::from_secs(1)
+            });
+
+        fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+    }
+
+    fn new_tls_configuration(name: &str, issuer: &Issuer) -> TlsConfiguration {
+        let dir = new_temp_dir(name);
+        let (certificate, key) = issue(issuer);
+        let configuration = TlsConfiguration::new(
+            dir.join("cert.pem").to_str().unwrap(),
+            dir.join("key.pem").to_str().unwrap(),
+        );
+
+        write(Path::new(&configuration.cert_file), &certificate.pem());
+        write(Path::new(&configuration.key_file), &key.serialize_pem());
+        configuration
+    }
+
+    fn new_client_config(issuer: &Issuer, client: Option<(Certificate, KeyPair)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(issuer.certificate.der().clone()).unwrap();
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+
+        match client {
+            Some((certificate, key)) => builder
+                .with_client_auth_cert(
+                    vec![certificate.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    /// Connects over an in-memory stream, returning the certificate served if
+    /// the server accepted the connection
+    async fn connect(
+        tls: Arc<ReloadableTlsConfig>,
+        client_config: ClientConfig,
+    ) -> Option<CertificateDer<'static>> {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut accepted = accept_tls(tokio_stream::iter(vec![Ok(server)]), tls);
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        let (connected, accepted) =
+            tokio::join!(connector.connect(server_name, client), accepted.next());
+
+        // With TLS 1.3 the client finishes before the server verifies its certificate
+        accepted?.ok()?;
+        connected
+            .ok()?
+            .get_ref()
+            .1
+            .peer_certificates()?
+            .first()
+            .cloned()
+    }
+
+    #[tokio::test]
+    async fn serves_reloaded_certificates() {
+        let issuer = new_issuer();
+        let configuration = new_tls_configuration("tls-reload", &issuer);
+        let tls = Arc::new(ReloadableTlsConfig::new(configuration.clone()).unwrap());
+        let served = connect(tls.clone(), new_client_config(&issuer, None)).await;
+        assert!(served.is_some());
+        assert!(!tls.refresh().unwrap());
+
+        let (certificate, key) = issue(&issuer);
+        write(Path::new(&configuration.cert_file), &certificate.pem());
+        write(Path::new(&configuration.key_file), &key.serialize_pem());
+        assert!(tls.refresh().unwrap());
+
+        let reloaded = connect(tls.clone(), new_client_config(&issuer, None)).await;
+        assert_eq!(reloaded.as_ref(), Some(certificate.der()));
+        assert_ne!(reloaded, served);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_previous_certificates_when_a_reload_fails() {
+        let issuer = new_issuer();
+        let configuration = new_tls_configuration("tls-invalid", &issuer);
+        let tls = Arc::new(ReloadableTlsConfig::new(configuration.clone()).unwrap());
+        let served = connect(tls.clone(), new_client_config(&issuer, None)).await;
+
+        write(Path::new(&configuration.cert_file), "not a certificate");
+        assert!(tls.refresh().is_err());
+
+        let kept = connect(tls.clone(), new_client_config(&issuer, None)).await;
+        assert!(kept.is_some());
+        assert_eq!(kept, served);
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates_signed_by_the_client_ca() {
+        let issuer = new_issuer();
+        let client_issuer = new_issuer();
+        let mut configuration = new_tls_configuration("tls-mtls", &issuer);
+        let client_ca_file = Path::new(&configuration.cert_file).with_file_name("client-ca.pem");
+        write(&client_ca_file, &client_issuer.certificate.pem());
+        configuration.client_ca_file = Some(client_ca_file.to_str().unwrap().to_string());
+        let tls = Arc::new(ReloadableTlsConfig::new(configuration).unwrap());
+
+        let client = new_client_config(&issuer, Some(issue(&client_issuer)));
+        assert!(connect(tls.clone(), client).await.is_some());
+
+        let client = new_client_config(&issuer, None);
+        assert!(connect(tls.clone(), client).await.is_none());
+
+        let client = new_client_config(&issuer, Some(issue(&issuer)));
+        assert!(connect(tls.clone(), client).await.is_none());
+    }
+}